@@ -0,0 +1,52 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use routs::http::{encode_response, HttpResponse, HttpStatus};
+
+// How response heads were built before `encode_response`: a String per header,
+// joined and formatted again with the status line.
+fn encode_with_format(response: &HttpResponse, body: &[u8]) -> Vec<u8> {
+    let status_line = match &response.raw_status {
+        Some((code, reason)) => format!("HTTP/1.1 {} {}\r\n", code, reason),
+        None => format!("HTTP/1.1 {}\r\n", response.status_code.to_string()),
+    };
+    let headers = response
+        .headers
+        .iter()
+        .map(|(k, v)| format!("{}: {}\r\n", k, v))
+        .collect::<String>();
+    let mut bytes = format!("{}{}\r\n", status_line, headers).into_bytes();
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+fn small_response() -> HttpResponse {
+    let mut response = HttpResponse::new(HttpStatus::OK);
+    for (key, value) in [
+        ("Content-Type", "application/json"),
+        ("Connection", "keep-alive"),
+        ("X-Request-Id", "18de4d6b8edf752f-0"),
+        ("Cache-Control", "no-store"),
+    ] {
+        response.headers.insert(key.to_string(), value.to_string());
+    }
+    response.body = br#"{"id":1,"name":"routs","ok":true}"#.to_vec();
+    let length = response.body.len().to_string();
+    response
+        .headers
+        .insert("Content-Length".to_string(), length);
+    response
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let response = small_response();
+    let mut group = c.benchmark_group("encode_response");
+    group.bench_function("presized", |b| {
+        b.iter(|| encode_response(black_box(&response), black_box(&response.body)))
+    });
+    group.bench_function("format", |b| {
+        b.iter(|| encode_with_format(black_box(&response), black_box(&response.body)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
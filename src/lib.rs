@@ -0,0 +1,3 @@
+mod routs;
+
+pub use routs::*;
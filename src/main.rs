@@ -1,8 +1,9 @@
 use std::sync::Arc;
 
-use routs::http::{self, HttpRequest, HttpStatus, Writer};
-
-mod routs;
+use routs::{
+    http::{self, HttpRequest, HttpStatus, Writer},
+    method::Method,
+};
 
 #[tokio::main]
 async fn main() {
@@ -11,12 +12,18 @@ async fn main() {
     router.handle_func("/", Arc::new(|w: &mut Writer, r: HttpRequest| {
             w.header().set("Access-Control-Allow-Methods", "GET");
 
-            if r.method != "POST" {
+            if r.method_enum() != Method::Post {
                 w.write_header(HttpStatus::MethodNotAllowed);
                 return;
             }
 
-            println!("{}", String::from_utf8_lossy(&r.body));
+            match r.body_str() {
+                Ok(body) => println!("{}", body),
+                Err(_) => {
+                    w.write_header(HttpStatus::BadRequest);
+                    return;
+                }
+            }
 
             w.write_header(HttpStatus::OK);
         }),
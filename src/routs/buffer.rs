@@ -1,9 +1,17 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::{config::Config, error::RequestError, http::HttpStatus};
 
 pub struct DynamicBuffer<T> {
     pub headers: Vec<u8>,
     pub stream: T,
     pub body: Vec<u8>,
+    // When the first byte of the current request arrived.
+    pub started: Option<Instant>,
+    // The longest a body read waits for more bytes; None waits forever.
+    pub read_timeout: Option<Duration>,
 }
 
 impl<T> DynamicBuffer<T> {
@@ -12,52 +20,340 @@ impl<T> DynamicBuffer<T> {
             headers: Vec::new(),
             stream,
             body: Vec::new(),
+            started: None,
+            read_timeout: None,
         }
     }
 
-    pub async fn read_headers_and_body(&mut self) -> Result<(), String>
+    // Reads until the end of the header block. Any body bytes that arrived in the
+    // same reads are kept in `body`; the rest is left on the stream for the parser.
+    // Returns false when the peer closed the connection before sending anything.
+    pub async fn read_headers(&mut self, config: &Config) -> Result<bool, RequestError>
     where
         T: AsyncReadExt + AsyncWriteExt + Unpin,
     {
         let mut buffer = [0; 1024];
-        let mut total_read = 0;
-        let mut header_end = false;
-        let mut content_length = 0;
+        // Bytes already scanned for the terminator; each pass only looks at new ones,
+        // less three in case it straddles two reads.
+        let mut searched: usize = 0;
+        // Where the first line not yet checked against the limits starts.
+        let mut unchecked: usize = 0;
+        self.started = None;
 
         loop {
+            // Empty lines before a request line are allowed and ignored.
+            while self.headers.starts_with(b"\r\n") {
+                self.headers.drain(..2);
+                searched = 0;
+                unchecked = 0;
+            }
+
+            if self.started.is_none() && !self.headers.is_empty() {
+                self.started = Some(Instant::now());
+            }
+
+            let start = searched.saturating_sub(3);
+            let end = self.headers[start..]
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .map(|pos| start + pos);
+            let block = match end {
+                Some(pos) => &self.headers[..pos],
+                None => &self.headers[..],
+            };
+            unchecked = check_limits(block, unchecked, config)?;
+
+            if let Some(pos) = end {
+                let remaining = self.headers.split_off(pos + 4);
+                self.body.extend_from_slice(&remaining);
+                return Ok(true);
+            }
+
+            searched = self.headers.len();
             let bytes_read = self.stream.read(&mut buffer).await.map_err(|e| e.to_string())?;
             if bytes_read == 0 {
-                break;
+                if self.headers.is_empty() {
+                    return Ok(false);
+                }
+                return Err("Connection closed before end of headers".into());
             }
 
             self.headers.extend_from_slice(&buffer[..bytes_read]);
-            total_read += bytes_read;
-
-            if !header_end {
-                if let Some(pos) = self.headers.windows(4).position(|window| window == b"\r\n\r\n") {
-                    header_end = true;
-
-                    let header_str = String::from_utf8_lossy(&self.headers[..pos]);
-                    for line in header_str.lines() {
-                        if let Some((key, value)) = line.split_once(':') {
-                            if key.trim().eq_ignore_ascii_case("Content-Length") {
-                                content_length = value.trim().parse().unwrap_or(0);
-                            }
-                        }
-                    }
-
-                    let remaining = &self.headers[(pos + 4)..];
-                    self.body.extend_from_slice(remaining);
-                }
-            } else {
-                self.body.extend_from_slice(&buffer[..bytes_read]);
+        }
+    }
+
+    // Decodes a chunked body (RFC 7230 4.1). Sizes must be plain hex, every chunk
+    // must end in CRLF, and the chunk-size line length, the number of chunks, each
+    // chunk's size and the decoded size are all bounded, since lax chunked parsing
+    // is a classic request smuggling vector. Trailer fields are read and discarded.
+    pub async fn read_chunked_body(
+        &mut self,
+        config: &Config,
+        max_body: Option<usize>,
+    ) -> Result<Vec<u8>, RequestError>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let mut body = Vec::new();
+        let mut pos = 0;
+        let mut chunks = 0;
+
+        loop {
+            let line_end = self.find_line(pos, config.max_chunk_line).await?;
+            let line = &self.body[pos..line_end];
+            let size = line.split(|b| *b == b';').next().unwrap_or_default();
+            let size = std::str::from_utf8(size)
+                .ok()
+                .filter(|size| !size.is_empty() && size.len() <= 16)
+                .filter(|size| size.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|size| usize::from_str_radix(size, 16).ok())
+                .ok_or("Invalid chunk size")?;
+            pos = line_end + 2;
+
+            chunks += 1;
+            if chunks > config.max_chunks {
+                return Err("Too many chunks".into());
             }
 
-            if self.body.len() >= content_length {
+            if size == 0 {
                 break;
             }
+
+            // The size comes off the wire, so nothing below may overflow on it.
+            if size > config.max_chunk_size {
+                return Err("Chunk too large".into());
+            }
+            let total = body.len().checked_add(size).ok_or("Chunked body too large")?;
+            if max_body.is_some_and(|max| total > max) {
+                return Err("Chunked body too large".into());
+            }
+            let data_end = pos.checked_add(size).ok_or("Invalid chunk size")?;
+            let chunk_end = data_end.checked_add(2).ok_or("Invalid chunk size")?;
+
+            self.read_exact_body(chunk_end).await?;
+            if &self.body[data_end..chunk_end] != b"\r\n" {
+                return Err("Chunk data not followed by CRLF".into());
+            }
+            body.extend_from_slice(&self.body[pos..data_end]);
+            pos = chunk_end;
+        }
+
+        let mut trailers = 0;
+        loop {
+            let line_end = self.find_line(pos, config.max_header_line).await?;
+            let empty = line_end == pos;
+            pos = line_end + 2;
+            if empty {
+                break;
+            }
+
+            trailers += 1;
+            if trailers > config.max_headers {
+                return Err("Too many trailer fields".into());
+            }
+        }
+
+        self.finish_request(pos);
+        Ok(body)
+    }
+
+    // Position of the CRLF ending the line that starts at `start` in `body`.
+    async fn find_line(&mut self, start: usize, max: usize) -> Result<usize, RequestError>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let mut searched = start;
+        loop {
+            if let Some(offset) = self.body[searched..].windows(2).position(|w| w == b"\r\n") {
+                let end = searched + offset;
+                if end - start > max {
+                    return Err("Chunk line too long".into());
+                }
+                return Ok(end);
+            }
+
+            if self.body.len() - start > max {
+                return Err("Chunk line too long".into());
+            }
+            searched = self.body.len().saturating_sub(1).max(start);
+            self.read_exact_body(self.body.len() + 1).await?;
+        }
+    }
+
+    // Reads from the stream until `body` holds at least `len` bytes, however short
+    // the individual reads. Bytes past `len` stay buffered for the next request.
+    pub async fn read_exact_body(&mut self, len: usize) -> Result<(), RequestError>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let mut buffer = [0; 1024];
+        while self.body.len() < len {
+            let bytes_read = read_within(&mut self.stream, &mut buffer, self.read_timeout).await?;
+            if bytes_read == 0 {
+                return Err("Connection closed before reading full body".into());
+            }
+            self.body.extend_from_slice(&buffer[..bytes_read]);
+        }
+        Ok(())
+    }
+
+    // Sends the interim 100 Continue a client asked for with Expect and waits up to
+    // `timeout` for its body to start. A client already sending the body needs
+    // neither.
+    pub async fn send_continue(&mut self, timeout: Duration) -> Result<(), RequestError>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        if !self.body.is_empty() {
+            return Ok(());
         }
 
+        self.stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .map_err(|e| e.to_string())?;
+        self.stream.flush().await.map_err(|e| e.to_string())?;
+
+        let mut buffer = [0; 1024];
+        let bytes_read = match tokio::time::timeout(timeout, self.stream.read(&mut buffer)).await {
+            Ok(read) => read.map_err(|e| e.to_string())?,
+            Err(_) => {
+                return Err(RequestError::new(
+                    HttpStatus::RequestTimeout,
+                    "Timed out waiting for the body after 100 Continue",
+                ))
+            }
+        };
+        if bytes_read == 0 {
+            return Err("Connection closed before sending the body".into());
+        }
+        self.body.extend_from_slice(&buffer[..bytes_read]);
         Ok(())
     }
+
+    // Drops the current request and keeps whatever followed its body, which is the
+    // start of the next pipelined request.
+    pub fn finish_request(&mut self, body_len: usize) {
+        let leftover = self.body.split_off(body_len.min(self.body.len()));
+        self.headers = leftover;
+        self.body.clear();
+    }
+}
+
+// One read that fails with a 408 when no bytes arrive within `timeout`. Bounding
+// each read rather than the whole body stops clients that stall without limiting
+// how long a large upload may take.
+pub async fn read_within<R>(
+    stream: &mut R,
+    buffer: &mut [u8],
+    timeout: Option<Duration>,
+) -> Result<usize, RequestError>
+where
+    R: AsyncRead + Unpin,
+{
+    let read = stream.read(buffer);
+    let read = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read).await.map_err(|_| {
+            RequestError::new(HttpStatus::RequestTimeout, "Timed out reading request body")
+        })?,
+        None => read.await,
+    };
+    read.map_err(|e| e.to_string().into())
+}
+
+// Checked while the block is still arriving, so an oversized line is rejected
+// before the rest of it has to be buffered. Lines before `from` passed on an
+// earlier read; returns where the last, possibly incomplete, line starts so the
+// next read only checks from there.
+fn check_limits(block: &[u8], from: usize, config: &Config) -> Result<usize, RequestError> {
+    if block.len() > config.max_header_size {
+        return Err(RequestError::new(
+            HttpStatus::RequestHeaderFieldsTooLarge,
+            "Header block too large",
+        ));
+    }
+
+    // The block shrinks to end before the terminator once that arrives.
+    let mut start = from.min(block.len());
+    loop {
+        let end = block[start..].iter().position(|b| *b == b'\n');
+        let line_length = end.unwrap_or(block.len() - start);
+        if line_length > config.max_header_line {
+            return Err(if start == 0 {
+                RequestError::new(HttpStatus::RequestURITooLong, "Request line too long")
+            } else {
+                RequestError::new(HttpStatus::RequestHeaderFieldsTooLarge, "Header line too long")
+            });
+        }
+
+        match end {
+            Some(end) => start += end + 1,
+            None => return Ok(start),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read_exact_body_loops_over_short_reads() {
+        // A 4-byte pipe never hands over more than 4 bytes per read.
+        let (mut client, server) = duplex(4);
+        let mut buffer = DynamicBuffer::new(server);
+        buffer.body.extend_from_slice(b"he");
+
+        let writer = tokio::spawn(async move {
+            client.write_all(b"llo world").await.unwrap();
+        });
+        buffer.read_exact_body(11).await.unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(buffer.body, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn read_exact_body_keeps_bytes_past_the_length() {
+        let stream = tokio::io::join(&b"abcdefgh"[..], tokio::io::sink());
+        let mut buffer = DynamicBuffer::new(stream);
+        buffer.read_exact_body(3).await.unwrap();
+        let read = buffer.body.len();
+
+        buffer.finish_request(3);
+        assert_eq!(buffer.headers, &b"abcdefgh"[3..read]);
+    }
+
+    #[tokio::test]
+    async fn read_exact_body_fails_when_the_stream_closes_early() {
+        let stream = tokio::io::join(&b"abc"[..], tokio::io::sink());
+        let mut buffer = DynamicBuffer::new(stream);
+        let e = buffer.read_exact_body(10).await.unwrap_err();
+
+        assert_eq!(e.status, HttpStatus::BadRequest);
+        assert_eq!(e.message, "Connection closed before reading full body");
+    }
+
+    #[tokio::test]
+    async fn read_exact_body_times_out_on_a_stalled_stream() {
+        let (_client, server) = duplex(64);
+        let mut buffer = DynamicBuffer::new(server);
+        buffer.read_timeout = Some(Duration::from_millis(20));
+        let e = buffer.read_exact_body(1).await.unwrap_err();
+
+        assert_eq!(e.status, HttpStatus::RequestTimeout);
+    }
+
+    #[tokio::test]
+    async fn read_exact_body_with_everything_buffered_reads_nothing() {
+        let (_client, server) = duplex(64);
+        let mut buffer = DynamicBuffer::new(server);
+        buffer.body.extend_from_slice(b"done");
+        buffer.read_timeout = Some(Duration::from_millis(20));
+
+        buffer.read_exact_body(4).await.unwrap();
+        assert_eq!(buffer.body, b"done");
+    }
 }
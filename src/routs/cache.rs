@@ -0,0 +1,165 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    header,
+    http::{Handler, HttpRequest, HttpStatus, Writer},
+    method::Method,
+    middleware::Middleware,
+};
+
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    // How long a stored response is served before the handler runs again.
+    pub ttl: Duration,
+    // The least recently used response is evicted to make room past this.
+    pub max_entries: usize,
+}
+
+impl CacheOptions {
+    pub fn new() -> Self {
+        CacheOptions {
+            ttl: Duration::from_secs(60),
+            max_entries: 1024,
+        }
+    }
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions::new()
+    }
+}
+
+struct Entry {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored: Instant,
+    used: u64,
+}
+
+// Entries by key, and their keys by last use so the oldest is found at once.
+struct Store {
+    entries: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Store {
+    fn get(&mut self, key: &str, ttl: Duration) -> Option<&Entry> {
+        let used = self.entries.get(key)?.used;
+        self.order.remove(&used);
+        if self.entries[key].stored.elapsed() >= ttl {
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_string());
+        let entry = self.entries.get_mut(key)?;
+        entry.used = self.tick;
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: String, mut entry: Entry, max_entries: usize) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.order.remove(&old.used);
+        }
+        while self.entries.len() >= max_entries {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        if max_entries == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        entry.used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+}
+
+// A middleware that stores 200 responses to GET requests, keyed by host, path and
+// query, and serves them without calling the handler until `ttl` runs out. HEAD
+// requests are answered from the stored GET responses.
+//
+// Responses are only stored when they are buffered bytes and safe to share between
+// clients: not for requests with Authorization, and not when the response sets
+// Cache-Control no-store or private, Set-Cookie, or Vary.
+pub fn response_cache(options: CacheOptions) -> Middleware {
+    let store = Arc::new(Mutex::new(Store {
+        entries: HashMap::new(),
+        order: BTreeMap::new(),
+        tick: 0,
+    }));
+
+    Arc::new(move |handler: Handler| {
+        let store = store.clone();
+        let options = options.clone();
+        Arc::new(move |w: &mut Writer, r: HttpRequest| {
+            let method = r.method_enum();
+            if (method != Method::Get && method != Method::Head)
+                || r.header(header::AUTHORIZATION).is_some()
+            {
+                handler(w, r);
+                return;
+            }
+
+            let host = r.host.as_deref().unwrap_or_default();
+            let key = format!("{} {}?{}", host, r.path, r.query().unwrap_or_default());
+            if let Some(entry) = store.lock().unwrap().get(&key, options.ttl) {
+                for (name, value) in &entry.headers {
+                    w.header().set(name, value);
+                }
+                w.header()
+                    .set(header::AGE, &entry.stored.elapsed().as_secs().to_string());
+                w.write_header(HttpStatus::OK);
+                w.write(&entry.body);
+                return;
+            }
+
+            handler(w, r);
+            if method != Method::Get || *w.status() != HttpStatus::OK || !shareable(w) {
+                return;
+            }
+            let Some(body) = w.buffered_body().map(<[u8]>::to_vec) else {
+                return;
+            };
+
+            let entry = Entry {
+                // The request ID belongs to the request that produced the response.
+                headers: w
+                    .header()
+                    .iter()
+                    .filter(|(name, _)| !name.eq_ignore_ascii_case(header::X_REQUEST_ID))
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                body,
+                stored: Instant::now(),
+                used: 0,
+            };
+            store
+                .lock()
+                .unwrap()
+                .insert(key, entry, options.max_entries);
+        })
+    })
+}
+
+fn shareable(w: &mut Writer) -> bool {
+    let headers = w.header();
+    let forbidden = headers.get(header::CACHE_CONTROL).is_some_and(|value| {
+        value.split(',').any(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case("no-store") || name.eq_ignore_ascii_case("private")
+        })
+    });
+
+    !forbidden && headers.get(header::SET_COOKIE).is_none() && headers.get(header::VARY).is_none()
+}
@@ -0,0 +1,137 @@
+use std::{collections::HashMap, env, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use super::{
+    error::{render_error, RequestError},
+    http::{HttpRequest, HttpResponse},
+};
+#[cfg(feature = "tls")]
+use super::ssl_tls::TlsOptions;
+
+// Runs right after a connection is accepted, before anything is read from it.
+// Returning an error closes the socket.
+pub type AcceptHook = Arc<dyn Fn(SocketAddr) -> Result<(), String> + Send + Sync>;
+
+// Runs on every request before it is routed, e.g. to strip a prefix or map legacy
+// URLs. Routing, limits and logging all see the path it leaves in `req.path`.
+pub type PathRewrite = Arc<dyn Fn(&mut HttpRequest) + Send + Sync>;
+
+// Renders the body of an error response the server generated itself, from the
+// error and the request's Accept header (None if it had none or couldn't be
+// parsed). Returns the Content-Type and the body.
+pub type ErrorRenderer =
+    Arc<dyn Fn(&RequestError, Option<&str>) -> (String, Vec<u8>) + Send + Sync>;
+
+// Runs on every handled response just before it is sent, after the handler and
+// middlewares are done with it.
+pub type Finalizer = Arc<dyn Fn(&mut HttpResponse) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Config {
+    pub upload_dir: PathBuf,
+    pub upload_threshold: Option<usize>,
+    pub shutdown_grace: Duration,
+    pub accept_backoff_min: Duration,
+    pub accept_backoff_max: Duration,
+    pub https_redirect: Option<u16>,
+    pub trust_forwarded_proto: bool,
+    pub proxy_protocol: bool,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub ipv6_only: bool,
+    pub log_bodies: bool,
+    pub log_body_limit: usize,
+    pub max_headers: usize,
+    pub max_header_line: usize,
+    pub max_path_segments: usize,
+    pub collapse_slashes: bool,
+    pub max_header_size: usize,
+    pub max_body_size: Option<usize>,
+    // Inflates gzip request bodies held in memory before handlers see them. Upload
+    // files and `stream_body` bodies stay compressed, Content-Encoding included.
+    pub decompress_requests: bool,
+    // Inflating stops with a 413 once the output is this many times the input.
+    pub max_decompression_ratio: Option<usize>,
+    pub max_chunk_line: usize,
+    pub max_chunks: usize,
+    // The largest single chunk of a chunked body, whatever `max_body_size` says.
+    pub max_chunk_size: usize,
+    pub default_headers: HashMap<String, String>,
+    pub keep_alive: bool,
+    pub keep_alive_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub continue_timeout: Duration,
+    pub max_request_time: Option<Duration>,
+    pub on_accept: Option<AcceptHook>,
+    pub rewrite: Option<PathRewrite>,
+    pub finalizer: Option<Finalizer>,
+    pub error_renderer: ErrorRenderer,
+    pub request_id: bool,
+    pub auto_head: bool,
+    pub auto_options: bool,
+    // Answers 500 when a handler neither writes a body nor sets a status.
+    pub strict_responses: bool,
+    // Answers a handler that wrote no body with a page describing the request.
+    // Meant for local development only.
+    pub dev_mode: bool,
+    // Added to text/* Content-Types that don't name a charset.
+    pub default_charset: String,
+    #[cfg(feature = "tls")]
+    pub tls: TlsOptions,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config {
+            upload_dir: env::temp_dir(),
+            upload_threshold: None,
+            shutdown_grace: Duration::from_secs(30),
+            accept_backoff_min: Duration::from_millis(5),
+            accept_backoff_max: Duration::from_secs(1),
+            https_redirect: None,
+            trust_forwarded_proto: false,
+            proxy_protocol: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            ipv6_only: false,
+            log_bodies: false,
+            log_body_limit: 1024,
+            max_headers: 100,
+            max_header_line: 8 * 1024,
+            max_path_segments: 128,
+            collapse_slashes: false,
+            max_header_size: 64 * 1024,
+            max_body_size: None,
+            decompress_requests: false,
+            max_decompression_ratio: Some(100),
+            max_chunk_line: 4 * 1024,
+            max_chunks: 16 * 1024,
+            max_chunk_size: 16 * 1024 * 1024,
+            default_headers: HashMap::new(),
+            keep_alive: true,
+            keep_alive_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            continue_timeout: Duration::from_secs(10),
+            max_request_time: None,
+            on_accept: None,
+            rewrite: None,
+            finalizer: None,
+            error_renderer: Arc::new(render_error),
+            request_id: true,
+            auto_head: true,
+            auto_options: true,
+            strict_responses: false,
+            dev_mode: false,
+            default_charset: "utf-8".to_string(),
+            #[cfg(feature = "tls")]
+            tls: TlsOptions::new(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Default)]
+pub struct Traffic {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl Traffic {
+    pub fn read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+pub struct CountingStream<T> {
+    inner: T,
+    traffic: Arc<Traffic>,
+}
+
+impl<T> CountingStream<T> {
+    pub fn new(inner: T, traffic: Arc<Traffic>) -> Self {
+        CountingStream { inner, traffic }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.traffic.read.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.traffic
+                .written
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Logs the teardown from Drop so it also runs when the task panics or is aborted.
+pub struct ConnectionLog {
+    socket: SocketAddr,
+    opened: Instant,
+    traffic: Arc<Traffic>,
+}
+
+impl ConnectionLog {
+    pub fn open(socket: SocketAddr, traffic: Arc<Traffic>) -> Self {
+        debug!("Connection opened: {}", socket);
+        ConnectionLog {
+            socket,
+            opened: Instant::now(),
+            traffic,
+        }
+    }
+}
+
+impl Drop for ConnectionLog {
+    fn drop(&mut self) {
+        debug!(
+            "Connection closed: {} ({} bytes read, {} bytes written, open for {:?})",
+            self.socket,
+            self.traffic.read(),
+            self.traffic.written(),
+            self.opened.elapsed()
+        );
+    }
+}
+
+// Connection counters for one server, shared by every clone of its router.
+#[derive(Default)]
+pub struct ServerStats {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    rejected: AtomicU64,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct StatsSnapshot {
+    pub accepted: u64,
+    pub active: u64,
+    // Closed right after accept, by a connection limit or the accept hook.
+    pub rejected: u64,
+}
+
+impl ServerStats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    // Open connections from `ip`. An IPv4 client on a dual-stack listener counts
+    // under its IPv4 address.
+    pub fn active_from(&self, ip: IpAddr) -> usize {
+        let per_ip = self.per_ip.lock().unwrap();
+        per_ip.get(&ip.to_canonical()).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn open(self: &Arc<Self>, ip: IpAddr) -> ActiveConnection {
+        let ip = ip.to_canonical();
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        *self.per_ip.lock().unwrap().entry(ip).or_default() += 1;
+        ActiveConnection {
+            stats: self.clone(),
+            ip,
+        }
+    }
+
+    pub(crate) fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Counts a connection as active until dropped with its task.
+pub struct ActiveConnection {
+    stats: Arc<ServerStats>,
+    ip: IpAddr,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+
+        let mut per_ip = self.stats.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use super::{error::RequestError, http::HttpStatus};
+
+// Inflates a gzip body. A small payload can expand enormously, so inflating stops
+// with a 413 as soon as the output passes `max_size` or grows to more than
+// `max_ratio` times the compressed input, whichever comes first.
+pub fn gunzip(
+    input: &[u8],
+    max_size: Option<usize>,
+    max_ratio: Option<usize>,
+) -> Result<Vec<u8>, RequestError> {
+    let ratio_limit = max_ratio.map(|ratio| input.len().saturating_mul(ratio));
+    let limit = match (max_size, ratio_limit) {
+        (Some(size), Some(ratio)) => Some(size.min(ratio)),
+        (size, ratio) => size.or(ratio),
+    };
+
+    let mut decoder = GzDecoder::new(input);
+    let mut output = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        let read = decoder
+            .read(&mut chunk)
+            .map_err(|_| RequestError::from("Invalid gzip body"))?;
+        if read == 0 {
+            return Ok(output);
+        }
+
+        output.extend_from_slice(&chunk[..read]);
+        if limit.is_some_and(|limit| output.len() > limit) {
+            return Err(RequestError::new(
+                HttpStatus::RequestEntityTooLarge,
+                "Decompressed request body too large",
+            ));
+        }
+    }
+}
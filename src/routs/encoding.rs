@@ -0,0 +1,123 @@
+use std::fmt::{self, Write};
+
+// A `%` at `position` that isn't followed by two hex digits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub position: usize,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid percent-encoding at byte {}", self.position)
+    }
+}
+
+// Decodes every %XX escape, in either case. Unlike form decoding, '+' is left as
+// it is, and an incomplete or non-hex escape is an error rather than kept.
+pub fn percent_decode(input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'%' {
+            decoded.push(input[i]);
+            i += 1;
+            continue;
+        }
+        let byte = input.get(i + 1..i + 3).and_then(hex_pair);
+        decoded.push(byte.ok_or(DecodeError { position: i })?);
+        i += 3;
+    }
+
+    Ok(decoded)
+}
+
+// Escapes everything but the unreserved characters of RFC 3986 (letters, digits
+// and "-._~"), as uppercase %XX. Safe for a single path segment or query value.
+pub fn percent_encode(input: &[u8]) -> String {
+    percent_encode_with(input, b"")
+}
+
+// Like `percent_encode`, also leaving the bytes in `safe` unescaped, e.g. b"/" to
+// encode a whole path. '%', spaces, controls and non-ASCII bytes are always escaped.
+pub fn percent_encode_with(input: &[u8], safe: &[u8]) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for &byte in input {
+        let unreserved = byte.is_ascii_alphanumeric() || b"-._~".contains(&byte);
+        if unreserved || (byte.is_ascii_graphic() && byte != b'%' && safe.contains(&byte)) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+pub(crate) fn hex_pair(pair: &[u8]) -> Option<u8> {
+    let digit = |byte: u8| (byte as char).to_digit(16);
+    Some((digit(pair[0])? * 16 + digit(pair[1])?) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_accepts_either_hex_case() {
+        assert_eq!(percent_decode(b"caf%C3%A9").unwrap(), "café".as_bytes());
+        assert_eq!(percent_decode(b"caf%c3%a9").unwrap(), "café".as_bytes());
+        assert_eq!(percent_decode(b"%2f%2F").unwrap(), b"//");
+    }
+
+    #[test]
+    fn decode_leaves_plain_bytes_and_plus_alone() {
+        assert_eq!(percent_decode(b"").unwrap(), b"");
+        assert_eq!(percent_decode(b"a+b c").unwrap(), b"a+b c");
+        assert_eq!(percent_decode(b"%00%FF").unwrap(), [0x00, 0xff]);
+        // A decoded '%' is not decoded again.
+        assert_eq!(percent_decode(b"%2541").unwrap(), b"%41");
+    }
+
+    #[test]
+    fn decode_rejects_incomplete_and_invalid_escapes() {
+        for (input, position) in [
+            (&b"%"[..], 0),
+            (b"100%", 3),
+            (b"a%4", 1),
+            (b"%G1", 0),
+            (b"%1G", 0),
+            (b"ok%20%zz", 5),
+            (b"%+1", 0),
+        ] {
+            assert_eq!(
+                percent_decode(input),
+                Err(DecodeError { position }),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn encode_escapes_all_but_unreserved() {
+        assert_eq!(percent_encode(b"AZaz09-._~"), "AZaz09-._~");
+        assert_eq!(percent_encode(b"a b/c?d&e=f%"), "a%20b%2Fc%3Fd%26e%3Df%25");
+        assert_eq!(percent_encode("é".as_bytes()), "%C3%A9");
+        assert_eq!(percent_encode(b"\0\x7f"), "%00%7F");
+    }
+
+    #[test]
+    fn encode_with_keeps_safe_bytes_but_never_percent() {
+        assert_eq!(percent_encode_with(b"/docs/a b", b"/"), "/docs/a%20b");
+        assert_eq!(percent_encode_with(b"50% off", b"% "), "50%25%20off");
+        assert_eq!(percent_encode_with("é".as_bytes(), &[0xc3, 0xa9]), "%C3%A9");
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let input: Vec<u8> = (0..=255).collect();
+        assert_eq!(
+            percent_decode(percent_encode(&input).as_bytes()).unwrap(),
+            input
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use serde_json::json;
+
+use super::{files::escape_html, header, http::HttpStatus};
+
+#[derive(Debug)]
+pub struct RequestError {
+    pub status: HttpStatus,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(status: HttpStatus, message: &str) -> Self {
+        RequestError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.status.to_string())
+    }
+}
+
+impl From<String> for RequestError {
+    fn from(message: String) -> Self {
+        RequestError {
+            status: HttpStatus::BadRequest,
+            message,
+        }
+    }
+}
+
+impl From<&str> for RequestError {
+    fn from(message: &str) -> Self {
+        RequestError::new(HttpStatus::BadRequest, message)
+    }
+}
+
+// The default `error_renderer`. Errors the server generates itself, like a 404 for
+// an unknown path or a 413 for an oversized body, are rendered as JSON, HTML or
+// plain text, whichever the client's Accept prefers. Plain text wins ties and is
+// used when there is no Accept at all.
+pub fn render_error(e: &RequestError, accept: Option<&str>) -> (String, Vec<u8>) {
+    let accept = accept.unwrap_or("text/plain");
+    let formats = ["text/plain", "application/json", "text/html"];
+    let mut chosen = "text/plain";
+    let mut top = 0;
+    for format in formats {
+        let quality = header::media_quality(accept, format);
+        if quality > top {
+            chosen = format;
+            top = quality;
+        }
+    }
+
+    let body = match chosen {
+        "application/json" => json!({
+            "status": e.status.code(),
+            "error": e.status.reason(),
+            "message": e.message,
+        })
+        .to_string(),
+        "text/html" => format!(
+            concat!(
+                "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body>\n",
+                "<h1>{0}</h1>\n<p>{1}</p>\n</body></html>\n",
+            ),
+            escape_html(e.status.to_string()),
+            escape_html(&e.message)
+        ),
+        _ => e.message.clone(),
+    };
+
+    (chosen.to_string(), body.into_bytes())
+}
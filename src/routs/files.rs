@@ -0,0 +1,477 @@
+use std::{
+    fmt::Write,
+    fs::{self, File},
+    io::{Cursor, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use log::error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{
+    encoding, header,
+    http::{Handler, HttpRequest, HttpStatus, Writer},
+    method::Method,
+    request_id,
+};
+
+// More ranges than this, or ranges adding up to more than the file, are answered
+// with the whole file instead of one part each.
+const MAX_RANGES: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct DirOptions {
+    // Lists a directory without an index.html instead of answering 404.
+    pub listing: bool,
+}
+
+impl DirOptions {
+    pub fn new() -> Self {
+        DirOptions { listing: false }
+    }
+}
+
+impl Default for DirOptions {
+    fn default() -> Self {
+        DirOptions::new()
+    }
+}
+
+// Serves the files under `dir` for request paths starting with `prefix`. A
+// pre-compressed `<file>.gz` next to a file is sent instead when the client
+// accepts gzip.
+pub fn serve_dir(prefix: &str, dir: impl Into<PathBuf>) -> Handler {
+    serve_dir_with(prefix, dir, DirOptions::new())
+}
+
+pub fn serve_dir_with(prefix: &str, dir: impl Into<PathBuf>, options: DirOptions) -> Handler {
+    let prefix = prefix.to_string();
+    let dir = dir.into();
+
+    Arc::new(move |w: &mut Writer, r: HttpRequest| {
+        let method = r.method_enum();
+        if method != Method::Get && method != Method::Head {
+            w.header().set(header::ALLOW, "GET, HEAD");
+            w.write_header(HttpStatus::MethodNotAllowed);
+            return;
+        }
+
+        let path = r.path.as_str();
+        let relative = path.strip_prefix(prefix.as_str()).unwrap_or(path);
+        let Some(mut file_path) = resolve(&dir, relative) else {
+            w.write_header(HttpStatus::NotFound);
+            return;
+        };
+        if file_path.is_dir() {
+            let index = file_path.join("index.html");
+            if options.listing && !index.is_file() {
+                list_dir(w, &r, &dir, &file_path, path);
+                return;
+            }
+            file_path = index;
+        }
+
+        match dir.canonicalize() {
+            Ok(root) => send_file(w, &r, &file_path, Some(&root)),
+            Err(_) => w.write_header(HttpStatus::NotFound),
+        }
+    })
+}
+
+pub fn serve_file(w: &mut Writer, r: &HttpRequest, path: &Path) {
+    send_file(w, r, path, None)
+}
+
+// Like `serve_file`, but with a `root` only a file (or `.gz` sidecar) whose real
+// location is under it is sent, so a symlink can't lead out of a served directory.
+fn send_file(w: &mut Writer, r: &HttpRequest, path: &Path, root: Option<&Path>) {
+    let inside = |path: &Path| {
+        root.is_none_or(|root| path.canonicalize().is_ok_and(|path| path.starts_with(root)))
+    };
+    if !path.is_file() || !inside(path) {
+        w.write_header(HttpStatus::NotFound);
+        return;
+    }
+
+    w.header().set(header::CONTENT_TYPE, content_type_for(path));
+
+    let mut send_path = path.to_path_buf();
+    let sidecar = gzip_sidecar(path);
+    if sidecar.is_file() && inside(&sidecar) {
+        w.header().set(header::VARY, header::ACCEPT_ENCODING);
+        if r.header(header::ACCEPT_ENCODING)
+            .is_some_and(|value| header::accepts_encoding(value, "gzip"))
+        {
+            w.header().set(header::CONTENT_ENCODING, "gzip");
+            send_path = sidecar;
+        }
+    }
+
+    let opened = File::open(&send_path).and_then(|file| Ok((file.metadata()?, file)));
+    let (metadata, file) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("Failed to open {}: {}", send_path.display(), e);
+            w.header().del(header::CONTENT_ENCODING);
+            w.write_header(HttpStatus::InternalServerError);
+            return;
+        }
+    };
+
+    let length = metadata.len();
+    let modified = metadata.modified().ok();
+    let mtime = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let etag = format!("\"{:x}-{:x}\"", length, mtime);
+    let last_modified = modified.map(header::http_date);
+
+    w.header().set(header::ACCEPT_RANGES, "bytes");
+    w.header().set(header::ETAG, &etag);
+    if let Some(last_modified) = &last_modified {
+        w.header().set(header::LAST_MODIFIED, last_modified);
+    }
+
+    let ranges = r
+        .header(header::RANGE)
+        .filter(|_| if_range_matches(r, &etag, last_modified.as_deref()))
+        .and_then(|value| header::parse_range(value, length));
+    match ranges.as_deref() {
+        Some([]) => {
+            w.header()
+                .set(header::CONTENT_RANGE, &format!("bytes */{}", length));
+            w.write_header(HttpStatus::RequestedRangeNotSatisfiable);
+        }
+        Some([(start, end)]) => send_range(w, file, *start, *end, length),
+        Some(ranges) if worth_splitting(ranges, length) => {
+            send_ranges(w, &send_path, ranges, length)
+        }
+        _ => w.write_stream(tokio::fs::File::from_std(file), Some(length)),
+    }
+}
+
+fn send_range(w: &mut Writer, mut file: File, start: u64, end: u64, length: u64) {
+    if let Err(e) = file.seek(SeekFrom::Start(start)) {
+        error!("Failed to seek to byte {}: {}", start, e);
+        w.write_header(HttpStatus::InternalServerError);
+        return;
+    }
+
+    let content_range = format!("bytes {}-{}/{}", start, end, length);
+    w.header().set(header::CONTENT_RANGE, &content_range);
+    w.write_header(HttpStatus::PartialContent);
+    w.write_stream(tokio::fs::File::from_std(file), Some(end - start + 1));
+}
+
+fn worth_splitting(ranges: &[(u64, u64)], length: u64) -> bool {
+    let total: u64 = ranges.iter().map(|(start, end)| end - start + 1).sum();
+    ranges.len() <= MAX_RANGES && total <= length
+}
+
+// Sends each range as a part of a multipart/byteranges body (RFC 7233 4.1), every
+// part with the file's Content-Type and its own Content-Range.
+fn send_ranges(w: &mut Writer, path: &Path, ranges: &[(u64, u64)], length: u64) {
+    let content_type = w
+        .header()
+        .get(header::CONTENT_TYPE)
+        .unwrap_or_default()
+        .to_string();
+    let boundary = request_id::generate();
+
+    let mut body: Box<dyn AsyncRead + Send + Unpin> = Box::new(tokio::io::empty());
+    let mut size = 0;
+    for &(start, end) in ranges {
+        let opened = File::open(path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(start))?;
+            Ok(file)
+        });
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to read {}: {}", path.display(), e);
+                w.write_header(HttpStatus::InternalServerError);
+                return;
+            }
+        };
+
+        let part = format!(
+            "\r\n--{}\r\n{}: {}\r\n{}: bytes {}-{}/{}\r\n\r\n",
+            boundary,
+            header::CONTENT_TYPE,
+            content_type,
+            header::CONTENT_RANGE,
+            start,
+            end,
+            length
+        );
+        size += part.len() as u64 + end - start + 1;
+        let data = tokio::fs::File::from_std(file).take(end - start + 1);
+        body = Box::new(body.chain(Cursor::new(part.into_bytes())).chain(data));
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    size += closing.len() as u64;
+    body = Box::new(body.chain(Cursor::new(closing.into_bytes())));
+
+    let multipart = format!("multipart/byteranges; boundary={}", boundary);
+    w.header().set(header::CONTENT_TYPE, &multipart);
+    w.write_header(HttpStatus::PartialContent);
+    w.write_stream(body, Some(size));
+}
+
+// A Range is only honoured under If-Range when the validator still matches
+// (RFC 7233 3.2): an entity-tag must be a strong match, a date must be exact.
+fn if_range_matches(r: &HttpRequest, etag: &str, last_modified: Option<&str>) -> bool {
+    match r.header(header::IF_RANGE).map(str::trim) {
+        None => true,
+        Some(value) if value.starts_with("W/") => false,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => last_modified == Some(value),
+    }
+}
+
+// Renders an autoindex-style page for `path`. Entries whose real location is
+// outside `root`, i.e. symlinks leading out of it, are left out.
+fn list_dir(w: &mut Writer, r: &HttpRequest, root: &Path, dir: &Path, path: &str) {
+    // Relative links only resolve against a path ending in a slash.
+    if !path.ends_with('/') {
+        w.redirect_with_query(r, &format!("{}/", path), HttpStatus::MovedPermanently);
+        return;
+    }
+
+    let (Ok(root), Ok(real_dir)) = (root.canonicalize(), dir.canonicalize()) else {
+        w.write_header(HttpStatus::NotFound);
+        return;
+    };
+    if !real_dir.starts_with(&root) {
+        w.write_header(HttpStatus::NotFound);
+        return;
+    }
+    let inside = |path: &Path| {
+        path.canonicalize()
+            .is_ok_and(|path| path.starts_with(&root))
+    };
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to list {}: {}", dir.display(), e);
+            w.write_header(HttpStatus::InternalServerError);
+            return;
+        }
+    };
+
+    let mut rows = Vec::new();
+    for entry in entries.flatten() {
+        let entry_path = entry.path();
+        let (Ok(name), Ok(metadata)) = (entry.file_name().into_string(), fs::metadata(&entry_path))
+        else {
+            continue;
+        };
+        if inside(&entry_path) {
+            rows.push((name, metadata));
+        }
+    }
+    // Directories first, then by name.
+    rows.sort_by(|(a, a_meta), (b, b_meta)| b_meta.is_dir().cmp(&a_meta.is_dir()).then(a.cmp(b)));
+
+    let title = format!("Index of {}", escape_html(path));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body>\n<h1>{0}</h1>\n<table>\n",
+        title
+    );
+    if real_dir != root {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (name, metadata) in &rows {
+        let slash = if metadata.is_dir() { "/" } else { "" };
+        let size = if metadata.is_dir() {
+            "-".to_string()
+        } else {
+            metadata.len().to_string()
+        };
+        let modified = metadata
+            .modified()
+            .map(header::http_date)
+            .unwrap_or_default();
+        let _ = writeln!(
+            page,
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            encoding::percent_encode(name.as_bytes()),
+            slash,
+            escape_html(name),
+            slash,
+            size,
+            modified
+        );
+    }
+    page.push_str("</table>\n</body></html>\n");
+
+    w.html(&page);
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Maps a request path onto `dir`, refusing anything that would climb out of it.
+// Each segment is percent-decoded first, the way listings encode names; one that
+// decodes to a separator or NUL is refused rather than split.
+fn resolve(dir: &Path, relative: &str) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+        let decoded = encoding::percent_decode(segment.as_bytes()).ok()?;
+        let segment = String::from_utf8(decoded).ok()?;
+        if segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        let mut components = Path::new(&segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => return None,
+        }
+    }
+
+    Some(path)
+}
+
+fn gzip_sidecar(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".gz");
+    PathBuf::from(sidecar)
+}
+
+// Text types are left without a charset; the server's default one is added when
+// the response is sent.
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") | Some("mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory under the system temp dir, removed again on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("routs-files-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(handler: &Handler, path: &str) -> Writer {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+        let socket = "127.0.0.1:40000".parse().unwrap();
+        let mut w = Writer::new();
+        handler(
+            &mut w,
+            HttpRequest::from_bytes(raw.as_bytes(), socket).unwrap(),
+        );
+        w
+    }
+
+    #[tokio::test]
+    async fn listed_names_that_need_encoding_are_served() {
+        let dir = TempDir::new("encoded");
+        fs::write(dir.0.join("my file.txt"), "hello").unwrap();
+        fs::write(dir.0.join("50%.txt"), "half").unwrap();
+        let handler = serve_dir_with("/", &dir.0, DirOptions { listing: true });
+
+        let listing = get(&handler, "/");
+        let page = String::from_utf8_lossy(listing.buffered_body().unwrap()).to_string();
+        assert!(page.contains("href=\"my%20file.txt\""), "{}", page);
+        assert!(page.contains("href=\"50%25.txt\""), "{}", page);
+
+        assert_eq!(*get(&handler, "/my%20file.txt").status(), HttpStatus::OK);
+        assert_eq!(*get(&handler, "/50%25.txt").status(), HttpStatus::OK);
+    }
+
+    #[test]
+    fn resolve_refuses_encoded_separators_and_escapes() {
+        let dir = Path::new("/srv");
+        assert_eq!(resolve(dir, "/a%20b/c"), Some(PathBuf::from("/srv/a b/c")));
+        for relative in [
+            "/a%2Fb",
+            "/a%5Cb",
+            "/a%00b",
+            "/%2E%2E/etc",
+            "/..",
+            "/a%zz",
+            "/%FF",
+        ] {
+            assert_eq!(resolve(dir, relative), None, "{}", relative);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_out_of_the_root_are_not_served() {
+        let outside = TempDir::new("outside");
+        fs::write(outside.0.join("secret.txt"), "secret").unwrap();
+        let dir = TempDir::new("symlinked");
+        fs::write(dir.0.join("page.txt"), "page").unwrap();
+        let link = |target: &Path, name: &str| {
+            std::os::unix::fs::symlink(target, dir.0.join(name)).unwrap()
+        };
+        link(&outside.0.join("secret.txt"), "escape");
+        link(&outside.0.join("secret.txt"), "page.txt.gz");
+        link(&dir.0.join("page.txt"), "alias.txt");
+        let handler = serve_dir("/", &dir.0);
+
+        assert_eq!(*get(&handler, "/escape").status(), HttpStatus::NotFound);
+        assert_eq!(*get(&handler, "/alias.txt").status(), HttpStatus::OK);
+
+        let raw = b"GET /page.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let mut w = Writer::new();
+        let socket = "127.0.0.1:40000".parse().unwrap();
+        handler(&mut w, HttpRequest::from_bytes(raw, socket).unwrap());
+        assert_eq!(*w.status(), HttpStatus::OK);
+        assert_eq!(w.header().get(header::CONTENT_ENCODING), None);
+    }
+}
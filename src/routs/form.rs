@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use super::encoding::hex_pair;
+
+// Parses an application/x-www-form-urlencoded string, such as a form body or a
+// query string, into its fields. The first value wins when a name repeats.
+pub fn parse(input: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    for (name, value) in parse_all(input) {
+        fields.entry(name).or_insert(value);
+    }
+    fields
+}
+
+// Every (name, value) pair in order, repeated names included.
+pub fn parse_all(input: &str) -> Vec<(String, String)> {
+    input
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+// Decodes one form component: '+' is a space and %XX an escaped byte. Malformed
+// escapes are kept as they are, invalid UTF-8 is replaced.
+pub fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => bytes.get(i + 1..i + 3).and_then(hex_pair),
+            _ => None,
+        };
+        match (escaped, bytes[i]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                i += 2;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,675 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    encoding,
+    error::RequestError,
+    http::{strip_port, HttpStatus},
+};
+
+pub const ACCEPT: &str = "Accept";
+pub const ACCEPT_ENCODING: &str = "Accept-Encoding";
+pub const ACCEPT_RANGES: &str = "Accept-Ranges";
+pub const AGE: &str = "Age";
+pub const ALLOW: &str = "Allow";
+pub const AUTHORIZATION: &str = "Authorization";
+pub const CACHE_CONTROL: &str = "Cache-Control";
+pub const CONNECTION: &str = "Connection";
+pub const CONTENT_DISPOSITION: &str = "Content-Disposition";
+pub const CONTENT_ENCODING: &str = "Content-Encoding";
+pub const CONTENT_LENGTH: &str = "Content-Length";
+pub const CONTENT_RANGE: &str = "Content-Range";
+pub const CONTENT_TYPE: &str = "Content-Type";
+pub const ETAG: &str = "ETag";
+pub const EXPECT: &str = "Expect";
+pub const HOST: &str = "Host";
+pub const IF_MATCH: &str = "If-Match";
+pub const IF_NONE_MATCH: &str = "If-None-Match";
+pub const IF_RANGE: &str = "If-Range";
+pub const LAST_MODIFIED: &str = "Last-Modified";
+pub const LOCATION: &str = "Location";
+pub const RANGE: &str = "Range";
+pub const SET_COOKIE: &str = "Set-Cookie";
+pub const TRAILER: &str = "Trailer";
+pub const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+pub const UPGRADE: &str = "Upgrade";
+pub const VARY: &str = "Vary";
+pub const WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+pub const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+pub const X_REQUEST_ID: &str = "X-Request-Id";
+
+// Header names are case-insensitive, so lookups must not rely on the map's keys.
+pub fn get<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+pub fn parse_content_length(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("Invalid Content-Length: {}", value));
+    }
+
+    value
+        .parse()
+        .map_err(|_| format!("Invalid Content-Length: {}", value))
+}
+
+// Chunked is the only transfer coding the server decodes, so it must be the one
+// and only coding listed. Other codings get a 501 instead of being passed through
+// undecoded, and a repeated chunked is ambiguous framing (RFC 7230 3.3.1, 3.3.3).
+pub fn check_transfer_encoding(value: &str) -> Result<(), RequestError> {
+    let codings: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect();
+
+    if let Some(coding) = codings.iter().find(|c| !c.eq_ignore_ascii_case("chunked")) {
+        return Err(RequestError::new(
+            HttpStatus::NotImplemented,
+            &format!("Unsupported Transfer-Encoding: {}", coding),
+        ));
+    }
+    if codings.len() != 1 {
+        return Err("Invalid Transfer-Encoding".into());
+    }
+
+    Ok(())
+}
+
+pub struct ContentType {
+    pub media_type: String,
+    pub charset: Option<String>,
+}
+
+pub fn parse_content_type(value: &str) -> Option<ContentType> {
+    let mut parts = value.split(';');
+    let media_type = parts.next()?.trim().to_ascii_lowercase();
+    if !media_type.contains('/') {
+        return None;
+    }
+
+    let charset = parts
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_ascii_lowercase());
+
+    Some(ContentType {
+        media_type,
+        charset,
+    })
+}
+
+impl ContentType {
+    // application/json and structured-syntax suffixes like application/vnd.api+json
+    // (RFC 6839). JSON is always UTF-8, so any other declared charset is rejected.
+    pub fn is_json(&self) -> bool {
+        let json = match self.media_type.split_once('/') {
+            Some(("application", subtype)) => subtype == "json" || subtype.ends_with("+json"),
+            _ => false,
+        };
+
+        let utf8 = self
+            .charset
+            .as_deref()
+            .is_none_or(|charset| charset == "utf-8");
+        json && utf8
+    }
+
+    pub fn is_form(&self) -> bool {
+        self.media_type == "application/x-www-form-urlencoded"
+    }
+}
+
+// A Cache-Control response value (RFC 9111 5.2.2), built up directive by
+// directive, e.g. `CacheControl::new().public().max_age(Duration::from_secs(3600))`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CacheControl {
+    public: bool,
+    private: bool,
+    no_cache: bool,
+    no_store: bool,
+    no_transform: bool,
+    must_revalidate: bool,
+    proxy_revalidate: bool,
+    immutable: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+    stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn new() -> Self {
+        CacheControl::default()
+    }
+
+    // Shared caches may store it. Clears `private`, as the two contradict.
+    pub fn public(mut self) -> Self {
+        self.public = true;
+        self.private = false;
+        self
+    }
+
+    // Only the client's own cache may store it. Clears `public`.
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self.public = false;
+        self
+    }
+
+    // Stored, but revalidated with the server before every use.
+    pub fn no_cache(mut self) -> Self {
+        self.no_cache = true;
+        self
+    }
+
+    // Never stored at all.
+    pub fn no_store(mut self) -> Self {
+        self.no_store = true;
+        self
+    }
+
+    pub fn no_transform(mut self) -> Self {
+        self.no_transform = true;
+        self
+    }
+
+    pub fn must_revalidate(mut self) -> Self {
+        self.must_revalidate = true;
+        self
+    }
+
+    pub fn proxy_revalidate(mut self) -> Self {
+        self.proxy_revalidate = true;
+        self
+    }
+
+    // The response never changes while fresh, e.g. a fingerprinted asset (RFC 8246).
+    pub fn immutable(mut self) -> Self {
+        self.immutable = true;
+        self
+    }
+
+    // Durations are sent in whole seconds, rounded down.
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age.as_secs());
+        self
+    }
+
+    pub fn s_maxage(mut self, age: Duration) -> Self {
+        self.s_maxage = Some(age.as_secs());
+        self
+    }
+
+    pub fn stale_while_revalidate(mut self, window: Duration) -> Self {
+        self.stale_while_revalidate = Some(window.as_secs());
+        self
+    }
+
+    pub fn stale_if_error(mut self, window: Duration) -> Self {
+        self.stale_if_error = Some(window.as_secs());
+        self
+    }
+}
+
+impl fmt::Display for CacheControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.public, "public"),
+            (self.private, "private"),
+            (self.no_cache, "no-cache"),
+            (self.no_store, "no-store"),
+            (self.no_transform, "no-transform"),
+            (self.must_revalidate, "must-revalidate"),
+            (self.proxy_revalidate, "proxy-revalidate"),
+            (self.immutable, "immutable"),
+        ];
+        let mut directives: Vec<String> = flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| name.to_string())
+            .collect();
+
+        let seconds = [
+            (self.max_age, "max-age"),
+            (self.s_maxage, "s-maxage"),
+            (self.stale_while_revalidate, "stale-while-revalidate"),
+            (self.stale_if_error, "stale-if-error"),
+        ];
+        for (value, name) in seconds {
+            if let Some(value) = value {
+                directives.push(format!("{}={}", name, value));
+            }
+        }
+
+        f.write_str(&directives.join(", "))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Disposition {
+    // Shown in the browser where possible.
+    Inline,
+    // Downloaded, under the filename if one is given.
+    Attachment,
+}
+
+// A Content-Disposition value (RFC 6266). A filename that isn't plain ASCII is
+// sent twice: as `filename*` in UTF-8 (RFC 5987) and as an ASCII `filename` with
+// the other characters replaced, for clients that don't know `filename*`.
+pub fn content_disposition(kind: Disposition, filename: Option<&str>) -> String {
+    let kind = match kind {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    let Some(filename) = filename else {
+        return kind.to_string();
+    };
+
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == filename {
+        return format!("{}; filename=\"{}\"", kind, fallback);
+    }
+
+    // attr-char from RFC 5987 3.2.1.
+    let encoded = encoding::percent_encode_with(filename.as_bytes(), b"!#$&+^`|");
+    format!("{}; filename=\"{}\"; filename*=UTF-8''{}", kind, fallback, encoded)
+}
+
+// Whether an Accept-Encoding value allows `coding`, see `AcceptEncoding::allows`.
+pub fn accepts_encoding(value: &str, coding: &str) -> bool {
+    parse_accept_encoding(value).allows(coding)
+}
+
+// An Accept-Encoding value as (coding, quality) pairs, quality in thousandths.
+pub struct AcceptEncoding {
+    codings: Vec<(String, u16)>,
+}
+
+// Items with a malformed quality are skipped. An empty value is valid and means
+// only identity is acceptable.
+pub fn parse_accept_encoding(value: &str) -> AcceptEncoding {
+    let codings = value
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim();
+            if name.is_empty() {
+                return None;
+            }
+
+            let mut quality = 1000;
+            for param in params {
+                if let Some((key, q)) = param.split_once('=') {
+                    if key.trim().eq_ignore_ascii_case("q") {
+                        quality = parse_quality(q.trim())?;
+                    }
+                }
+            }
+            Some((name.to_ascii_lowercase(), quality))
+        })
+        .collect();
+
+    AcceptEncoding { codings }
+}
+
+impl AcceptEncoding {
+    // How much the client wants `coding` (RFC 7231 5.3.4): its own entry, else the
+    // "*" entry. Identity is acceptable unless excluded by "identity;q=0" or
+    // "*;q=0"; anything else unlisted is not.
+    pub fn quality(&self, coding: &str) -> u16 {
+        match self.listed(coding) {
+            Some(quality) => quality,
+            None if coding.eq_ignore_ascii_case("identity") => 1000,
+            None => 0,
+        }
+    }
+
+    fn listed(&self, coding: &str) -> Option<u16> {
+        let find = |name: &str| {
+            self.codings
+                .iter()
+                .find(|(listed, _)| listed.eq_ignore_ascii_case(name))
+                .map(|(_, quality)| *quality)
+        };
+        find(coding).or_else(|| find("*"))
+    }
+
+    pub fn allows(&self, coding: &str) -> bool {
+        self.quality(coding) > 0
+    }
+
+    // The listed codings the client accepts, most preferred first. Equal qualities
+    // keep the client's order, and identity comes last unless listed.
+    pub fn preferred(&self) -> Vec<&str> {
+        let mut accepted: Vec<(&str, u16)> = self
+            .codings
+            .iter()
+            .filter(|(name, quality)| *quality > 0 && name != "*")
+            .map(|(name, quality)| (name.as_str(), *quality))
+            .collect();
+        accepted.sort_by_key(|(_, quality)| std::cmp::Reverse(*quality));
+
+        let mut preferred: Vec<&str> = accepted.into_iter().map(|(name, _)| name).collect();
+        if !preferred.contains(&"identity") && self.allows("identity") {
+            preferred.push("identity");
+        }
+        preferred
+    }
+
+    // Picks which of the server's `available` codings to send: the one the client
+    // rates highest, the earlier in `available` on a tie. Identity the client didn't
+    // list is only the fallback. None when nothing is acceptable, which calls for a
+    // 406 Not Acceptable.
+    pub fn negotiate<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        let mut best: Option<(&'a str, u16)> = None;
+        for coding in available {
+            let quality = self.listed(coding).unwrap_or(0);
+            if quality > 0 && best.is_none_or(|(_, top)| quality > top) {
+                best = Some((coding, quality));
+            }
+        }
+
+        best.map(|(coding, _)| coding).or_else(|| {
+            available
+                .iter()
+                .find(|coding| coding.eq_ignore_ascii_case("identity") && self.allows(coding))
+                .copied()
+        })
+    }
+}
+
+// How much an Accept value wants `media_type`, in thousandths: the quality of the
+// most specific range matching it, "type/subtype" over "type/*" over "*/*"
+// (RFC 7231 5.3.2). 0 when nothing matches.
+pub fn media_quality(accept: &str, media_type: &str) -> u16 {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    let mut best: Option<(u8, u16)> = None;
+
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let range = params.next().unwrap_or_default().trim();
+        let specificity = if range.eq_ignore_ascii_case(media_type) {
+            3
+        } else if range.strip_suffix("/*").is_some_and(|t| t.eq_ignore_ascii_case(kind)) {
+            2
+        } else if range == "*/*" {
+            1
+        } else {
+            continue;
+        };
+
+        let quality = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1000), |(_, q)| parse_quality(q.trim()));
+        let Some(quality) = quality else {
+            continue;
+        };
+        if best.is_none_or(|(top, _)| specificity > top) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map_or(0, |(_, quality)| quality)
+}
+
+// qvalue = ( "0" [ "." 0*3DIGIT ] ) / ( "1" [ "." 0*3("0") ] ), in thousandths.
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let thousandths: u16 = format!("{:0<3}", fraction).parse().ok()?;
+    match whole {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+// IMF-fixdate from RFC 7231 7.1.1.1, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let days = secs / 86400;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // Days since the epoch to a civil date, after Howard Hinnant's algorithm.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+// The entity-tags of an If-Match or If-None-Match value, each as sent, e.g.
+// `"xyz"` or `W/"xyz"`. "*" comes back on its own; malformed entries are skipped.
+pub fn parse_etags(value: &str) -> Vec<String> {
+    let value = value.trim();
+    if value == "*" {
+        return vec![value.to_string()];
+    }
+
+    let mut tags = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return tags;
+        }
+
+        // An opaque tag may contain commas, so it runs to the closing quote.
+        let start = if rest.starts_with("W/") { 2 } else { 0 };
+        let end = rest[start..]
+            .strip_prefix('"')
+            .and_then(|opaque| opaque.find('"'))
+            .map(|close| start + close + 2);
+        match end {
+            Some(end) => {
+                tags.push(rest[..end].to_string());
+                rest = &rest[end..];
+            }
+            None => rest = rest.split_once(',').map_or("", |(_, next)| next),
+        }
+    }
+}
+
+// Strong comparison (RFC 9110 8.8.3.2): both tags strong and identical.
+pub fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+// Weak comparison: identical once any W/ prefix is dropped.
+pub fn weak_match(a: &str, b: &str) -> bool {
+    a.strip_prefix("W/").unwrap_or(a) == b.strip_prefix("W/").unwrap_or(b)
+}
+
+// Byte ranges from a Range header as inclusive (start, end) pairs, clamped to
+// `length`. None means the header isn't a valid bytes range and should be ignored;
+// an empty list means none of the ranges can be satisfied.
+pub fn parse_range(value: &str, length: u64) -> Option<Vec<(u64, u64)>> {
+    let ranges = value.trim().strip_prefix("bytes=")?;
+    let mut satisfiable = Vec::new();
+
+    for range in ranges.split(',') {
+        let (start, end) = range.trim().split_once('-')?;
+        let digits = |value: &str| value.bytes().all(|b| b.is_ascii_digit());
+        if !digits(start) || !digits(end) {
+            return None;
+        }
+
+        let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+            (Some(start), Some(end)) if start > end => return None,
+            (Some(start), end) if start < length => {
+                Some((start, end.map_or(length - 1, |end| end.min(length - 1))))
+            }
+            (Some(_), _) => None,
+            (None, Some(suffix)) if suffix > 0 && length > 0 => {
+                Some((length - suffix.min(length), length - 1))
+            }
+            (None, Some(_)) => None,
+            (None, None) => return None,
+        };
+        satisfiable.extend(range);
+    }
+
+    Some(satisfiable)
+}
+
+pub fn parse_host(value: &str) -> Option<(String, Option<u16>)> {
+    let value = value.trim();
+    let name = strip_port(value);
+    if name.is_empty() {
+        return None;
+    }
+
+    let port = value[name.len()..]
+        .strip_prefix(':')
+        .and_then(|port| port.parse().ok());
+
+    Some((name.to_ascii_lowercase(), port))
+}
+
+// tchar from RFC 7230 3.2.6.
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^'
+                        | b'_' | b'`' | b'|' | b'~'
+                )
+        })
+}
+
+// Splits a header block on CRLF. A bare CR or LF stays inside its line and is
+// rejected by the line parsers.
+pub fn split_lines(block: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(block);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        match current.windows(2).position(|window| window == b"\r\n") {
+            Some(pos) => {
+                rest = Some(&current[pos + 2..]);
+                Some(&current[..pos])
+            }
+            None => {
+                rest = None;
+                Some(current)
+            }
+        }
+    })
+}
+
+// Parses one "name: value" line strictly per RFC 7230 3.2: the name must be a
+// token with no whitespace before the colon, obsolete line folding is refused, and
+// the value may not contain control characters other than HTAB.
+pub fn parse_field_line(line: &[u8]) -> Result<(String, String), RequestError> {
+    if line.first().is_some_and(|b| *b == b' ' || *b == b'\t') {
+        return Err("Invalid header: obsolete line folding".into());
+    }
+
+    let colon = line
+        .iter()
+        .position(|b| *b == b':')
+        .ok_or("Invalid header: missing colon")?;
+    let name = std::str::from_utf8(&line[..colon])
+        .ok()
+        .filter(|name| is_token(name))
+        .ok_or("Invalid header: invalid field name")?;
+
+    let value = &line[colon + 1..];
+    if value
+        .iter()
+        .any(|b| (b.is_ascii_control() && *b != b'\t') || *b == 0x7f)
+    {
+        return Err("Invalid header: control character in field value".into());
+    }
+
+    let value = String::from_utf8_lossy(value);
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_line_trims_optional_whitespace() {
+        let parsed = |line: &[u8]| parse_field_line(line).unwrap();
+        assert_eq!(parsed(b"Host: example.com"), ("Host".into(), "example.com".into()));
+        assert_eq!(parsed(b"Host:example.com"), ("Host".into(), "example.com".into()));
+        assert_eq!(parsed(b"X-Pad: \t a b \t"), ("X-Pad".into(), "a b".into()));
+        assert_eq!(parsed(b"X-Empty:"), ("X-Empty".into(), "".into()));
+        assert_eq!(parsed(b"X-Time: 10:30"), ("X-Time".into(), "10:30".into()));
+    }
+
+    #[test]
+    fn field_line_rejects_obfuscated_names() {
+        for line in [
+            &b"Host : example.com"[..],
+            b"Host\t: example.com",
+            b" Host: example.com",
+            b"\tHost: example.com",
+            b"Ho st: example.com",
+            b": example.com",
+            b"Host",
+            b"Host\0: example.com",
+            b"H\xc3\xa9st: example.com",
+            b"Host(): example.com",
+        ] {
+            assert!(parse_field_line(line).is_err(), "accepted {:?}", line);
+        }
+    }
+
+    #[test]
+    fn field_line_rejects_control_characters_in_values() {
+        for line in [
+            &b"Host: a\rb"[..],
+            b"Host: a\nb",
+            b"Host: a\0b",
+            b"Host: a\x7fb",
+            b"Host: a\x1bb",
+        ] {
+            assert!(parse_field_line(line).is_err(), "accepted {:?}", line);
+        }
+        assert!(parse_field_line(b"X-Tab: a\tb").is_ok());
+    }
+}
@@ -1,281 +1,2673 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_rustls::TlsAcceptor;
-use log::{error, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    future::{pending, Future},
+    io::Read,
+    net::SocketAddr,
+    path::PathBuf,
+    str::Utf8Error,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use socket2::{Domain, Protocol, Socket, Type};
+use serde::de::DeserializeOwned;
+#[cfg(feature = "tls")]
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use log::{debug, error, info, warn};
+#[cfg(feature = "tracing")]
+use tracing::{info_span, Instrument};
 
-use super::{buffer::DynamicBuffer, ssl_tls::configure_tls};
+use super::{
+    buffer::DynamicBuffer,
+    config::Config,
+    decompress,
+    connection::{ActiveConnection, ConnectionLog, CountingStream, ServerStats, Traffic},
+    error::RequestError,
+    files::{self, DirOptions},
+    form,
+    header::{self, CacheControl, Disposition},
+    method::Method,
+    middleware::{redirect_to_https, Middleware},
+    proxy,
+    request_id,
+    route::RouteBuilder,
+    upgrade::{Hijack, Upgraded},
+    upload::{stream_to_disk, UploadedFile},
+};
+#[cfg(feature = "tls")]
+use super::ssl_tls::{configure_sni_tls, configure_tls, SniResolver};
+
+// Stands in for the TLS acceptor when the "tls" feature is off; it has no values,
+// so `serve` can only ever be handed `None`.
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+enum TlsAcceptor {}
 
 pub struct HttpRequest {
     pub method: String,
+    pub target: RequestTarget,
+    // The path routing matches on, without the query string.
     pub path: String,
+    pub version: String,
+    pub host: Option<String>,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    pub upload: Option<UploadedFile>,
+    // Set instead of `body` on routes registered with `Router::stream_body`.
+    pub body_chunks: Option<BodyChunks>,
+    pub body_size: usize,
+    pub content_length: Option<usize>,
+    // The route that matched, as registered; set once the request is routed.
+    pub matched_pattern: Option<String>,
     pub socket: SocketAddr,
+    // When the header block was complete.
+    pub received_at: Instant,
+    deadline: Option<Instant>,
+    secure: bool,
+    raw_query: Option<String>,
 }
 
 impl HttpRequest {
-    pub async fn parser<T>(mut buffer: DynamicBuffer<T>, socket: SocketAddr) -> Result<Self, String>
+    pub async fn parser<T>(
+        buffer: &mut DynamicBuffer<T>,
+        socket: SocketAddr,
+        config: &Config,
+    ) -> Result<Self, RequestError>
     where
         T: AsyncReadExt + AsyncWriteExt + Unpin,
     {
-        let headers = buffer.headers.clone();
-        let request_str = String::from_utf8_lossy(&headers);
-        let mut lines = request_str.lines();
+        let mut req = HttpRequest::parse_head(&buffer.headers, socket, config)?;
+        req.check_body_size(config.max_body_size)?;
+        if req.expects_continue() {
+            buffer.send_continue(config.continue_timeout).await?;
+        }
+        req.read_body(buffer, config, config.max_body_size).await?;
+        Ok(req)
+    }
+
+    // Parses a complete request, head and body, from memory, e.g. to replay a
+    // captured request or to test a handler without a connection. It runs the same
+    // parser and limits as requests read from a socket, with the default config.
+    // Bytes after the request are ignored.
+    pub fn from_bytes(bytes: &[u8], socket: SocketAddr) -> Result<Self, RequestError> {
+        HttpRequest::from_bytes_with(bytes, socket, &Config::new())
+    }
+
+    // Same as `from_bytes` with `config`'s limits. The body is always kept in
+    // memory, whatever `upload_threshold` says.
+    pub fn from_bytes_with(
+        bytes: &[u8],
+        socket: SocketAddr,
+        config: &Config,
+    ) -> Result<Self, RequestError> {
+        let config = Config {
+            upload_threshold: None,
+            ..config.clone()
+        };
+        let mut buffer = DynamicBuffer::new(tokio::io::join(bytes, tokio::io::sink()));
+        let parsed = async {
+            if !buffer.read_headers(&config).await? {
+                return Err("Invalid HTTP request: Empty request".into());
+            }
+            let mut req = HttpRequest::parse_head(&buffer.headers, socket, &config)?;
+            req.check_body_size(config.max_body_size)?;
+            req.receive_body(&mut buffer, &config, config.max_body_size)
+                .await?;
+            Ok(req)
+        };
+
+        // Reads from memory never wait, so a single poll runs the parse to the end.
+        let mut parsed = std::pin::pin!(parsed);
+        match parsed.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err("Request could not be parsed from memory".into()),
+        }
+    }
+
+    // Parses the request line and headers; the body is left unread.
+    pub fn parse_head(
+        block: &[u8],
+        socket: SocketAddr,
+        config: &Config,
+    ) -> Result<Self, RequestError> {
+        let block = block.strip_suffix(b"\r\n\r\n").unwrap_or(block);
+        let mut lines = header::split_lines(block);
 
         let first_line = lines.next().ok_or("Invalid HTTP request: Missing request line")?;
+        let first_line = std::str::from_utf8(first_line)
+            .ok()
+            .filter(|line| line.is_ascii())
+            .ok_or("Invalid HTTP request: Request line is not ASCII")?;
         let mut parts = first_line.split_whitespace();
         let method = parts.next().ok_or("No method")?.to_string();
-        let path = parts.next().ok_or("No path")?.to_string();
+        if !header::is_token(&method) {
+            return Err("Invalid HTTP request: Invalid method".into());
+        }
+        let raw_target = parts.next().ok_or("No path")?;
+        let version = parts.next().unwrap_or("HTTP/1.0").to_string();
+        if !version.starts_with("HTTP/") || parts.next().is_some() {
+            return Err("Invalid HTTP request: Malformed request line".into());
+        }
+        // Checked before the target so an HTTP/2 connection preface ("PRI * HTTP/2.0")
+        // gets a 505 rather than a confusing 400.
+        if !version.starts_with("HTTP/1.") {
+            return Err(RequestError::new(
+                HttpStatus::HTTPVersionNotSupported,
+                "Only HTTP/1.x is supported",
+            ));
+        }
+        let target = RequestTarget::parse(raw_target, &method)?;
+        let (path, raw_query) = match target.path().split_once('?') {
+            Some((path, query)) => (path, Some(query.split('#').next().unwrap_or_default())),
+            None => (target.path(), None),
+        };
+        // Counted as slashes, so "//////" is as deep as "/a/b/c/d/e/f".
+        let segments = path.bytes().filter(|b| *b == b'/').count();
+        if segments > config.max_path_segments {
+            return Err(RequestError::new(
+                HttpStatus::RequestURITooLong,
+                "Too many path segments",
+            ));
+        }
 
-        let mut headers = HashMap::new();
-        for line in lines.by_ref() {
-            if line.is_empty() {
-                break;
+        let mut headers: HashMap<String, String> = HashMap::new();
+        // Field lines, not names: repeating one name must not get around the limit.
+        for (count, line) in lines.enumerate() {
+            if count >= config.max_headers {
+                return Err(RequestError::new(
+                    HttpStatus::RequestHeaderFieldsTooLarge,
+                    "Too many headers",
+                ));
+            }
+            let (key, value) = header::parse_field_line(line)?;
+
+            // Repeated fields are combined into one comma-separated value (RFC 7230 3.2.2).
+            let existing = headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(&key));
+            if let Some((_, existing)) = existing {
+                existing.push_str(", ");
+                existing.push_str(&value);
+                continue;
+            }
+
+            headers.insert(key, value);
+        }
+
+        // An absolute-form target carries its own authority, which wins over Host.
+        let host = match &target {
+            RequestTarget::Absolute { authority, .. } => Some(authority.as_str()),
+            _ => header::get(&headers, header::HOST),
+        };
+        let host = host.and_then(header::parse_host).map(|(name, _)| name);
+        let path = if config.collapse_slashes {
+            collapse_slashes(path)
+        } else {
+            path.to_string()
+        };
+        let raw_query = raw_query.map(str::to_string);
+
+        let content_length = match header::get(&headers, header::CONTENT_LENGTH) {
+            Some(value) => Some(header::parse_content_length(value)?),
+            None => None,
+        };
+
+        // With both, the two ends of a proxy chain may disagree on where the body
+        // ends, which is how requests get smuggled (RFC 7230 3.3.3).
+        if content_length.is_some() && header::get(&headers, header::TRANSFER_ENCODING).is_some() {
+            return Err("Both Content-Length and Transfer-Encoding are present".into());
+        }
+        if let Some(value) = header::get(&headers, header::TRANSFER_ENCODING) {
+            header::check_transfer_encoding(value)?;
+        }
+
+        // 100-continue is the only expectation defined (RFC 7231 5.1.1).
+        if let Some(expect) = header::get(&headers, header::EXPECT) {
+            if !expect.eq_ignore_ascii_case("100-continue") {
+                return Err(RequestError::new(
+                    HttpStatus::ExpectationFailed,
+                    "Unsupported expectation",
+                ));
+            }
+        }
+
+        Ok(HttpRequest {
+            method,
+            target,
+            body: Vec::new(),
+            body_size: 0,
+            content_length,
+            headers,
+            path,
+            version,
+            host,
+            upload: None,
+            body_chunks: None,
+            matched_pattern: None,
+            received_at: Instant::now(),
+            deadline: None,
+            secure: false,
+            raw_query,
+            socket,
+        })
+    }
+
+    // Reads the body, waiting at most `config.body_timeout` for each read.
+    async fn read_body<T>(
+        &mut self,
+        buffer: &mut DynamicBuffer<T>,
+        config: &Config,
+        max_body: Option<usize>,
+    ) -> Result<(), RequestError>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        buffer.read_timeout = Some(config.body_timeout);
+        self.receive_body(buffer, config, max_body).await
+    }
+
+    // Reads the body announced by Content-Length into memory, or into a file once it
+    // is past the upload threshold. Chunked bodies are always kept in memory.
+    async fn receive_body<T>(
+        &mut self,
+        buffer: &mut DynamicBuffer<T>,
+        config: &Config,
+        max_body: Option<usize>,
+    ) -> Result<(), RequestError>
+    where
+        T: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        if self.is_chunked() {
+            self.body = buffer.read_chunked_body(config, max_body).await?;
+            self.body_size = self.body.len();
+            return self.decompress(config, max_body);
+        }
+
+        let content_length = match self.content_length {
+            // Nothing to read, whether the length is absent or an explicit zero;
+            // anything already buffered belongs to the next request.
+            None | Some(0) => {
+                buffer.finish_request(0);
+                return Ok(());
+            }
+            Some(content_length) => content_length,
+        };
+
+        if let Some(threshold) = config.upload_threshold {
+            if content_length > threshold {
+                let file = stream_to_disk(
+                    &mut buffer.stream,
+                    &buffer.body,
+                    content_length,
+                    &config.upload_dir,
+                    buffer.read_timeout,
+                )
+                .await?;
+                // Written as received; a gzip upload keeps its Content-Encoding.
+                self.body_size = file.size();
+                self.upload = Some(file);
+                buffer.finish_request(content_length);
+                return Ok(());
+            }
+        }
+
+        buffer.read_exact_body(content_length).await?;
+        self.body = buffer.body[..content_length].to_vec();
+        self.body_size = content_length;
+        buffer.finish_request(content_length);
+        self.decompress(config, max_body)
+    }
+
+    // With `decompress_requests`, replaces a gzip body with the inflated one, held to
+    // the body limit and `max_decompression_ratio`. Content-Encoding is removed only
+    // here, so handlers see it exactly when the body is still encoded: other codings,
+    // upload files and `stream_body` bodies are never inflated.
+    fn decompress(&mut self, config: &Config, max_body: Option<usize>) -> Result<(), RequestError> {
+        let gzip = self.header(header::CONTENT_ENCODING).is_some_and(|coding| {
+            let coding = coding.trim();
+            coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip")
+        });
+        if !config.decompress_requests || !gzip {
+            return Ok(());
+        }
+
+        self.body = decompress::gunzip(&self.body, max_body, config.max_decompression_ratio)?;
+        self.body_size = self.body.len();
+        self.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(header::CONTENT_ENCODING));
+        Ok(())
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        header::get(&self.headers, key)
+    }
+
+    // The body as text. Invalid UTF-8 is an error rather than replaced, so
+    // handlers decide what a malformed body means.
+    pub fn body_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+
+    // The raw query string, without the leading '?' and undecoded.
+    pub fn query(&self) -> Option<&str> {
+        self.raw_query.as_deref()
+    }
+
+    // The first value of the query parameter `name`, decoded.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_all(name).into_iter().next()
+    }
+
+    // Every value of the query parameter `name` in order, e.g. both tags in
+    // "?tag=a&tag=b".
+    pub fn query_all(&self, name: &str) -> Vec<String> {
+        form::parse_all(self.query().unwrap_or_default())
+            .into_iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    // Whether the client asks to switch to `protocol`, e.g. "websocket". Upgrade
+    // only counts when Connection lists it too (RFC 7230 6.7). Requests to switch
+    // to a protocol no handler takes over, like h2c, are just served as HTTP/1.1.
+    pub fn wants_upgrade(&self, protocol: &str) -> bool {
+        let offered = self.header(header::UPGRADE).is_some_and(|value| {
+            value.split(',').any(|offer| {
+                let name = offer.trim().split('/').next().unwrap_or_default();
+                name.eq_ignore_ascii_case(protocol)
+            })
+        });
+        offered && has_token(self.header(header::CONNECTION), "upgrade")
+    }
+
+    // Whether the client holds its body back until it gets a 100 Continue. HTTP/1.0
+    // clients don't know interim responses.
+    fn expects_continue(&self) -> bool {
+        let has_body = self.content_length.is_some_and(|length| length > 0) || self.is_chunked();
+        self.version == "HTTP/1.1"
+            && has_body
+            && self
+                .header(header::EXPECT)
+                .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+    }
+
+    // Rejects a declared Content-Length over `max` before any of the body is read.
+    fn check_body_size(&self, max: Option<usize>) -> Result<(), RequestError> {
+        match (self.content_length, max) {
+            (Some(length), Some(max)) if length > max => Err(RequestError::new(
+                HttpStatus::RequestEntityTooLarge,
+                "Request body too large",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Chunked must be the last transfer coding applied to a request body.
+    pub fn is_chunked(&self) -> bool {
+        self.header(header::TRANSFER_ENCODING)
+            .and_then(|value| value.rsplit(',').next())
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    // Deserializes a JSON body, answering 415 when the Content-Type isn't JSON.
+    pub fn json<D: DeserializeOwned>(&self) -> Result<D, RequestError> {
+        let is_json = self
+            .header(header::CONTENT_TYPE)
+            .and_then(header::parse_content_type)
+            .is_some_and(|content_type| content_type.is_json());
+        if !is_json {
+            return Err(RequestError::new(
+                HttpStatus::UnsupportedMediaType,
+                "Expected a JSON Content-Type",
+            ));
+        }
+
+        serde_json::from_slice(&self.body).map_err(|e| format!("Invalid JSON body: {}", e).into())
+    }
+
+    // The fields of an application/x-www-form-urlencoded body, answering 415 for any
+    // other Content-Type.
+    pub fn form(&self) -> Result<HashMap<String, String>, RequestError> {
+        let is_form = self
+            .header(header::CONTENT_TYPE)
+            .and_then(header::parse_content_type)
+            .is_some_and(|content_type| content_type.is_form());
+        if !is_form {
+            return Err(RequestError::new(
+                HttpStatus::UnsupportedMediaType,
+                "Expected a form Content-Type",
+            ));
+        }
+
+        Ok(form::parse(&String::from_utf8_lossy(&self.body)))
+    }
+
+    // Parses the body according to its Content-Type: JSON, form fields, text for
+    // text/*, and bytes for application/octet-stream or no Content-Type at all.
+    // Other types are a 415.
+    pub fn parse_body(&self) -> Result<Body, RequestError> {
+        let Some(value) = self.header(header::CONTENT_TYPE) else {
+            return Ok(Body::Bytes(self.body.clone()));
+        };
+        let content_type = header::parse_content_type(value).ok_or_else(|| {
+            RequestError::new(HttpStatus::UnsupportedMediaType, "Invalid Content-Type")
+        })?;
+
+        if content_type.is_json() {
+            return self.json().map(Body::Json);
+        }
+        if content_type.is_form() {
+            return self.form().map(Body::Form);
+        }
+        if content_type.media_type.starts_with("text/") {
+            let text = self
+                .body_str()
+                .map_err(|e| format!("Invalid UTF-8 body: {}", e))?;
+            return Ok(Body::Text(text.to_string()));
+        }
+        if content_type.media_type == "application/octet-stream" {
+            return Ok(Body::Bytes(self.body.clone()));
+        }
+
+        Err(RequestError::new(
+            HttpStatus::UnsupportedMediaType,
+            &format!("Unsupported Content-Type: {}", content_type.media_type),
+        ))
+    }
+
+    // The entity-tags of If-Match, see `header::parse_etags`. None without the header.
+    pub fn if_match(&self) -> Option<Vec<String>> {
+        self.header(header::IF_MATCH).map(header::parse_etags)
+    }
+
+    pub fn if_none_match(&self) -> Option<Vec<String>> {
+        self.header(header::IF_NONE_MATCH).map(header::parse_etags)
+    }
+
+    // Evaluates If-Match and If-None-Match against the resource's current ETag,
+    // None if it doesn't exist (RFC 9110 13.2.2). A failed If-Match is a 412; a
+    // failed If-None-Match is a 304 for GET and HEAD and a 412 otherwise.
+    pub fn check_preconditions(&self, etag: Option<&str>) -> Result<(), RequestError> {
+        if let Some(tags) = self.if_match() {
+            let matched = etag.is_some_and(|etag| {
+                tags.iter()
+                    .any(|tag| tag == "*" || header::strong_match(tag, etag))
+            });
+            if !matched {
+                return Err(RequestError::new(
+                    HttpStatus::PreconditionFailed,
+                    "If-Match precondition failed",
+                ));
+            }
+        }
+
+        if let Some(tags) = self.if_none_match() {
+            let matched = etag.is_some_and(|etag| {
+                tags.iter()
+                    .any(|tag| tag == "*" || header::weak_match(tag, etag))
+            });
+            if matched {
+                let status = match self.method_enum() {
+                    Method::Get | Method::Head => HttpStatus::NotModified,
+                    _ => HttpStatus::PreconditionFailed,
+                };
+                return Err(RequestError::new(status, "If-None-Match precondition failed"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copies the rest of the body into `sink` and returns the number of bytes copied.
+    // On `stream_body` routes this takes `body_chunks` and forwards the body while it
+    // is still arriving, without buffering it; otherwise the body comes from memory
+    // or the upload file. Content-Length and chunked framing are already decoded.
+    // Streamed and in-memory bodies are moved out, so copying again sends nothing; the
+    // upload file stays in place.
+    pub async fn copy_body_to<W>(&mut self, sink: &mut W) -> std::io::Result<u64>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let copied = match (self.body_chunks.take(), &self.upload) {
+            (Some(mut chunks), _) => tokio::io::copy(&mut chunks, sink).await?,
+            (None, Some(file)) => {
+                let mut file = tokio::fs::File::open(file.path()).await?;
+                tokio::io::copy(&mut file, sink).await?
+            }
+            (None, None) => {
+                let body = std::mem::take(&mut self.body);
+                sink.write_all(&body).await?;
+                body.len() as u64
+            }
+        };
+
+        sink.flush().await?;
+        Ok(copied)
+    }
+
+    // Reads the body, from memory or from the upload file, failing once it goes past
+    // `max` bytes.
+    pub fn body_reader(&self, max: usize) -> std::io::Result<BodyReader<'_>> {
+        let inner: Box<dyn Read + '_> = match &self.upload {
+            Some(file) => Box::new(std::fs::File::open(file.path())?),
+            None => Box::new(self.body.as_slice()),
+        };
+
+        Ok(BodyReader {
+            inner,
+            remaining: max,
+        })
+    }
+
+    // When the server will give up on this request: `received_at` plus the route's
+    // timeout, or the end of `max_request_time` if sooner. None without either. Pass
+    // it on to downstream calls to fail fast.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    // Whether the request came over TLS, or through a proxy that says so in
+    // X-Forwarded-Proto when `trust_forwarded_proto` is on.
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    // An absolute URL for `path` on the host the request was sent to, e.g. for a
+    // Location header or pagination links. Without a usable Host the path comes
+    // back as it is, which clients resolve against the request URL.
+    pub fn url_for(&self, path: &str) -> String {
+        let authority = match &self.target {
+            RequestTarget::Absolute { authority, .. } => Some(authority.as_str()),
+            _ => self.header(header::HOST),
+        };
+        // Anything else could smuggle a different host or a path into the URL.
+        let authority = authority.map(str::trim).filter(|authority| {
+            header::parse_host(authority).is_some()
+                && authority
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-._:[]".contains(&b))
+        });
+        let Some(authority) = authority else {
+            return path.to_string();
+        };
+
+        let scheme = if self.secure { "https" } else { "http" };
+        let slash = if path.starts_with('/') { "" } else { "/" };
+        format!("{}://{}{}{}", scheme, authority.to_ascii_lowercase(), slash, path)
+    }
+
+    // The method as a `Method`; `method` keeps the string from the request line.
+    pub fn method_enum(&self) -> Method {
+        Method::from(self.method.as_str())
+    }
+
+    pub fn is_safe(&self) -> bool {
+        self.method_enum().is_safe()
+    }
+
+    pub fn is_idempotent(&self) -> bool {
+        self.method_enum().is_idempotent()
+    }
+
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let credentials = self.authorization("Basic")?;
+        let decoded = STANDARD.decode(credentials).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        Some((username.to_string(), password.to_string()))
+    }
+
+    pub fn bearer_token(&self) -> Option<String> {
+        self.authorization("Bearer").map(|token| token.to_string())
+    }
+
+    fn authorization(&self, scheme: &str) -> Option<&str> {
+        let value = self.header(header::AUTHORIZATION)?.trim();
+        let (name, credentials) = value.split_once(' ')?;
+        if !name.eq_ignore_ascii_case(scheme) {
+            return None;
+        }
+
+        let credentials = credentials.trim();
+        if credentials.is_empty() {
+            return None;
+        }
+
+        Some(credentials)
+    }
+}
+
+// A request body as `parse_body` understood it.
+#[derive(Clone, Debug)]
+pub enum Body {
+    Json(serde_json::Value),
+    Form(HashMap<String, String>),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+pub struct BodyReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    remaining: usize,
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            // Only an error if the body actually continues past the limit.
+            return match self.inner.read(&mut [0; 1])? {
+                0 => Ok(0),
+                _ => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Request body exceeds the size limit",
+                )),
+            };
+        }
+
+        let len = buf.len().min(self.remaining);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read;
+        Ok(read)
+    }
+}
+
+// A body handed to a streaming route as it arrives. Streaming handlers run on a
+// blocking thread, where reading waits for the next chunk from the connection.
+pub struct BodyChunks {
+    receiver: mpsc::Receiver<Result<Vec<u8>, String>>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl BodyChunks {
+    fn channel() -> (mpsc::Sender<Result<Vec<u8>, String>>, Self) {
+        let (sender, receiver) = mpsc::channel(4);
+        let chunks = BodyChunks {
+            receiver,
+            pending: Vec::new(),
+            offset: 0,
+        };
+        (sender, chunks)
+    }
+
+    // The next chunk of the body, or None once all of it has been received.
+    pub fn next_chunk(&mut self) -> Option<Result<Vec<u8>, String>> {
+        if self.offset < self.pending.len() {
+            let rest = self.pending.split_off(self.offset);
+            self.offset = 0;
+            self.pending.clear();
+            return Some(Ok(rest));
+        }
+
+        self.receiver.blocking_recv()
+    }
+}
+
+impl Read for BodyChunks {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.offset == self.pending.len() {
+            match self.receiver.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                Some(Err(e)) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e))
+                }
+                None => return Ok(0),
             }
+        }
+
+        let len = buf.len().min(self.pending.len() - self.offset);
+        buf[..len].copy_from_slice(&self.pending[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
 
-            if let Some((key, value)) = line.split_once(':') {
-                headers.insert(key.trim().to_string(), value.trim().to_string());
+// The same stream for async code, e.g. to `tokio::io::copy` it somewhere.
+impl AsyncRead for BodyChunks {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.offset == self.pending.len() {
+            match ready!(self.receiver.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                Some(Err(e)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        e,
+                    )))
+                }
+                None => return Poll::Ready(Ok(())),
             }
         }
 
-        let body = if let Some(content_length) = headers.get("Content-Length") {
-            let content_length = content_length.parse::<usize>().map_err(|e| e.to_string())?;
-        
-            while buffer.body.len() < content_length {
-                let mut chunk = vec![0; 1024];
-                let bytes_read = buffer.stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
-                if bytes_read == 0 {
-                    return Err("Connection closed before reading full body".to_string());
-                }
-                buffer.body.extend_from_slice(&chunk[..bytes_read]);
-            }
-        
-            buffer.body[..content_length].to_vec()
-        } else {
-            Vec::new()
-        };
-        
+        let len = buf.remaining().min(self.pending.len() - self.offset);
+        let start = self.offset;
+        buf.put_slice(&self.pending[start..start + len]);
+        self.offset += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct HttpResponse {
+    pub status_code: HttpStatus,
+    // Code and reason sent on the status line instead of `status_code`'s, which
+    // still decides how the server treats the response.
+    pub raw_status: Option<(u16, String)>,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub stream: Option<BodyStream>,
+}
+
+impl HttpResponse {
+    pub fn new(status_code: HttpStatus) -> Self {
+        HttpResponse {
+            status_code,
+            raw_status: None,
+            headers: HashMap::new(),
+            body: Vec::new(),
+            stream: None,
+        }
+    }
+}
+
+// Produces the trailer fields of a chunked body once all of it has been sent.
+pub type TrailerFn = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+
+pub struct BodyStream {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    length: Option<u64>,
+    trailers: Option<TrailerFn>,
+}
+
+impl BodyStream {
+    pub fn new<R>(reader: R, length: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        BodyStream {
+            reader: Box::new(reader),
+            length,
+            trailers: None,
+        }
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&mut Writer, HttpRequest) + Send + Sync>;
+
+// Anything that can answer a request. Closures get it for free; implement it on a
+// struct to keep state next to the code that uses it.
+pub trait RequestHandler: Send + Sync {
+    fn handle(&self, w: &mut Writer, r: HttpRequest);
+}
+
+impl<F> RequestHandler for F
+where
+    F: Fn(&mut Writer, HttpRequest) + Send + Sync,
+{
+    fn handle(&self, w: &mut Writer, r: HttpRequest) {
+        self(w, r)
+    }
+}
+
+// What the registration methods accept: a `Handler`, an `Arc` of any
+// `RequestHandler`, or an `Arc<dyn RequestHandler>`.
+pub trait IntoHandler {
+    fn into_handler(self) -> Handler;
+}
+
+impl IntoHandler for Handler {
+    fn into_handler(self) -> Handler {
+        self
+    }
+}
+
+impl<T: RequestHandler + 'static> IntoHandler for Arc<T> {
+    fn into_handler(self) -> Handler {
+        Arc::new(move |w: &mut Writer, r: HttpRequest| self.handle(w, r))
+    }
+}
+
+impl IntoHandler for Arc<dyn RequestHandler> {
+    fn into_handler(self) -> Handler {
+        Arc::new(move |w: &mut Writer, r: HttpRequest| self.handle(w, r))
+    }
+}
+
+pub struct Router {
+    routes: HashMap<String, Handler>,
+    methods: HashMap<String, HashMap<Method, Handler>>,
+    hosts: HashMap<String, HashMap<String, Handler>>,
+    timeouts: HashMap<String, Duration>,
+    body_limits: HashMap<String, usize>,
+    streamed: HashSet<String>,
+    prefixes: Vec<(String, Handler)>,
+    middlewares: Vec<Middleware>,
+    route_middlewares: HashMap<String, Vec<Middleware>>,
+    stats: Arc<ServerStats>,
+    config: Config,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: HashMap::new(),
+            methods: HashMap::new(),
+            hosts: HashMap::new(),
+            timeouts: HashMap::new(),
+            body_limits: HashMap::new(),
+            streamed: HashSet::new(),
+            prefixes: Vec::new(),
+            middlewares: Vec::new(),
+            route_middlewares: HashMap::new(),
+            stats: Arc::new(ServerStats::default()),
+            config: Config::new(),
+        }
+    }
+
+    // Connection counters, live for as long as the server runs. Take the handle
+    // before passing the router to `init`.
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.stats.clone()
+    }
+
+    pub fn config(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    pub fn handle_func(&mut self, path: &str, handler: impl IntoHandler) {
+        self.routes.insert(path.to_string(), handler.into_handler());
+    }
+
+    // Handles every path starting with `prefix` that has no route of its own. The
+    // longest matching prefix wins.
+    pub fn handle_prefix(&mut self, prefix: &str, handler: impl IntoHandler) {
+        self.prefixes.retain(|(existing, _)| existing != prefix);
+        self.prefixes.push((prefix.to_string(), handler.into_handler()));
+        self.prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    }
+
+    // Serves the files in `dir` under `prefix`, see `files::serve_dir`.
+    pub fn serve_dir(&mut self, prefix: &str, dir: impl Into<PathBuf>) {
+        self.handle_prefix(prefix, files::serve_dir(prefix, dir));
+    }
+
+    pub fn serve_dir_with(&mut self, prefix: &str, dir: impl Into<PathBuf>, options: DirOptions) {
+        self.handle_prefix(prefix, files::serve_dir_with(prefix, dir, options));
+    }
+
+    fn get_prefix_handler(&self, path: &str) -> Option<&(String, Handler)> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+    }
+
+    // Serves bytes compiled into the binary, e.g. with include_bytes!.
+    pub fn serve_bytes(&mut self, path: &str, bytes: &'static [u8], content_type: &str) {
+        let content_type = content_type.to_string();
+        self.handle_func(
+            path,
+            Arc::new(move |w: &mut Writer, _: HttpRequest| {
+                w.header().set(header::CONTENT_TYPE, &content_type);
+                w.write(bytes);
+            }),
+        );
+    }
+
+    // Adds a middleware inside the ones already registered.
+    pub fn use_middleware(&mut self, middleware: Middleware) {
+        self.middlewares.push(middleware);
+    }
+
+    // Adds a middleware at `index` in the chain, 0 being the outermost. Panics if
+    // `index` is past the end of the chain.
+    pub fn insert_middleware(&mut self, index: usize, middleware: Middleware) {
+        self.middlewares.insert(index, middleware);
+    }
+
+    // Adds a middleware for one path only. It runs inside the router-wide ones.
+    pub fn use_route_middleware(&mut self, path: &str, middleware: Middleware) {
+        self.route_middlewares
+            .entry(path.to_string())
+            .or_default()
+            .push(middleware);
+    }
+
+    fn wrap(&self, path: &str, handler: Handler) -> Handler {
+        let route = self.route_middlewares.get(path).map_or(&[][..], Vec::as_slice);
+        self.middlewares
+            .iter()
+            .chain(route)
+            .rev()
+            .fold(handler, |handler, middleware| middleware(handler))
+    }
+
+    // Starts configuring the route at `path`, see `RouteBuilder`.
+    pub fn route(&mut self, path: &str) -> RouteBuilder<'_> {
+        RouteBuilder::new(self, path)
+    }
+
+    pub fn set_timeout(&mut self, path: &str, timeout: Duration) {
+        self.timeouts.insert(path.to_string(), timeout);
+    }
+
+    // Overrides `max_body_size` for requests to `path`.
+    pub fn set_body_limit(&mut self, path: &str, limit: usize) {
+        self.body_limits.insert(path.to_string(), limit);
+    }
+
+    fn body_limit(&self, path: &str) -> Option<usize> {
+        self.body_limits
+            .get(path)
+            .copied()
+            .or(self.config.max_body_size)
+    }
+
+    // Hands request bodies on this path to the handler through `body_chunks` while
+    // they are still arriving, instead of reading them first.
+    pub fn stream_body(&mut self, path: &str) {
+        self.streamed.insert(path.to_string());
+    }
+
+    pub fn handle_method(&mut self, method: Method, path: &str, handler: impl IntoHandler) {
+        self.handle_methods(&[method], path, handler);
+    }
+
+    pub fn handle_methods(&mut self, methods: &[Method], path: &str, handler: impl IntoHandler) {
+        assert!(
+            !methods.is_empty(),
+            "handle_methods requires at least one method for path: {}",
+            path
+        );
+
+        let handler = handler.into_handler();
+        let routes = self.methods.entry(path.to_string()).or_default();
+        for method in methods {
+            routes.insert(method.clone(), handler.clone());
+        }
+    }
+
+    pub fn get_handler(&self, path: &str) -> Option<&Handler> {
+        self.routes.get(path)
+    }
+
+    pub fn get_method_handler(&self, method: &Method, path: &str) -> Option<&Handler> {
+        self.methods.get(path).and_then(|routes| routes.get(method))
+    }
+
+    fn new_writer(&self) -> Writer {
+        let mut writer = Writer::new();
+        for (key, value) in &self.config.default_headers {
+            writer.header().set(key, value);
+        }
+        writer
+    }
+
+    // Host-specific routes win, then method-specific ones, then any-method routes.
+    pub fn find_handler(
+        &self,
+        host: Option<&str>,
+        method: &Method,
+        path: &str,
+    ) -> Option<&Handler> {
+        host.and_then(|host| self.hosts.get(host))
+            .and_then(|routes| routes.get(path))
+            .or_else(|| self.get_method_handler(method, path))
+            .or_else(|| self.head_fallback(method, path))
+            .or_else(|| self.get_handler(path))
+            .or_else(|| self.get_prefix_handler(path).map(|(_, handler)| handler))
+    }
+
+    // The registered route a path is served by: the path itself for exact routes,
+    // "<prefix>*" for prefix routes.
+    fn pattern_for(&self, path: &str) -> String {
+        let exact = self.routes.contains_key(path)
+            || self.methods.contains_key(path)
+            || self.hosts.values().any(|routes| routes.contains_key(path));
+
+        match self.get_prefix_handler(path) {
+            Some((prefix, _)) if !exact => format!("{}*", prefix),
+            _ => path.to_string(),
+        }
+    }
+
+    // HEAD is answered by the GET handler, with the body dropped when sending.
+    fn head_fallback(&self, method: &Method, path: &str) -> Option<&Handler> {
+        if *method != Method::Head || !self.config.auto_head {
+            return None;
+        }
+
+        self.get_method_handler(&Method::Get, path)
+    }
+
+    // Methods supported anywhere on the server, for "OPTIONS *". TRACE is never
+    // advertised.
+    pub fn server_methods(&self) -> Vec<Method> {
+        let mut methods: Vec<Method> = self
+            .methods
+            .values()
+            .flat_map(|routes| routes.keys().cloned())
+            .filter(|method| *method != Method::Trace)
+            .collect();
+
+        if !self.routes.is_empty() || !self.hosts.is_empty() {
+            methods.extend(any_route_methods());
+        }
+        methods.push(Method::Options);
+
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods.dedup();
+        methods
+    }
+
+    // Methods with a handler on `path`, as listed in Allow for its 405 and OPTIONS
+    // responses. Empty when the path has no method-specific routes, since any-method
+    // and prefix routes take every method and no 405 is ever sent for them. An
+    // any-method route next to method-specific ones adds the common methods it serves.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods: Vec<Method> = self
+            .methods
+            .get(path)
+            .map(|routes| routes.keys().cloned().collect())
+            .unwrap_or_default();
+        if !methods.is_empty() && self.routes.contains_key(path) {
+            methods.extend(any_route_methods());
+        }
+
+        if self.config.auto_head && methods.contains(&Method::Get) {
+            methods.push(Method::Head);
+        }
+        if self.config.auto_options && !methods.is_empty() {
+            methods.push(Method::Options);
+        }
+
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods.dedup();
+        methods
+    }
+
+    pub fn handle_host_func(&mut self, host: &str, path: &str, handler: impl IntoHandler) {
+        self.hosts
+            .entry(host.to_ascii_lowercase())
+            .or_default()
+            .insert(path.to_string(), handler.into_handler());
+    }
+
+    pub fn get_host_handler(&self, host: Option<&str>, path: &str) -> Option<&Handler> {
+        host.and_then(|host| self.hosts.get(host))
+            .and_then(|routes| routes.get(path))
+            .or_else(|| self.get_handler(path))
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+// What an any-method route is advertised as serving.
+fn any_route_methods() -> [Method; 6] {
+    [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Patch,
+    ]
+}
+
+impl Clone for Router {
+    fn clone(&self) -> Self {
+        Router {
+            routes: self.routes.clone(),
+            methods: self.methods.clone(),
+            hosts: self.hosts.clone(),
+            timeouts: self.timeouts.clone(),
+            body_limits: self.body_limits.clone(),
+            streamed: self.streamed.clone(),
+            prefixes: self.prefixes.clone(),
+            middlewares: self.middlewares.clone(),
+            route_middlewares: self.route_middlewares.clone(),
+            stats: self.stats.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+pub struct Header {
+    headers: HashMap<String, String>,
+}
+
+impl Header {
+    fn new() -> Self {
+        Header {
+            headers: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        header::get(&self.headers, key)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.del(key);
+        self.add(key, value);
+        self
+    }
+
+    pub fn del(&mut self, key: &str) -> &mut Self {
+        self.headers.retain(|k, _| !k.eq_ignore_ascii_case(key));
+        self
+    }
+
+    fn add(&mut self, key: &str, value: &str) {
+        self.headers.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+pub struct Writer {
+    header: Header,
+    status_code: HttpStatus,
+    raw_status: Option<(u16, String)>,
+    body: Vec<u8>,
+    stream: Option<BodyStream>,
+    declared_length: Option<u64>,
+    // Whether the handler wrote a body or set a status, for `strict_responses`.
+    responded: bool,
+    hijack: Option<Hijack>,
+    trailers: Option<(Vec<String>, TrailerFn)>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Self {
+        Writer {
+            header: Header::new(),
+            status_code: HttpStatus::OK,
+            raw_status: None,
+            body: Vec::new(),
+            stream: None,
+            declared_length: None,
+            responded: false,
+            hijack: None,
+            trailers: None,
+        }
+    }
+
+    pub fn header(&mut self) -> &mut Header {
+        &mut self.header
+    }
+
+    // A plain 200 with no body, stream or hijack: what an untouched writer sends.
+    fn wrote_nothing(&self) -> bool {
+        self.body.is_empty()
+            && self.stream.is_none()
+            && self.hijack.is_none()
+            && self.raw_status.is_none()
+            && self.status_code == HttpStatus::OK
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.body = data.to_vec();
+        self.stream = None;
+        self.responded = true;
+    }
+
+    pub fn html(&mut self, markup: &str) {
+        self.header.set(header::CONTENT_TYPE, "text/html; charset=utf-8");
+        self.write(markup.as_bytes());
+    }
+
+    pub fn text(&mut self, text: &str) {
+        self.header.set(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+        self.write(text.as_bytes());
+    }
+
+    pub fn cache_control(&mut self, directives: &CacheControl) {
+        self.header.set(header::CACHE_CONTROL, &directives.to_string());
+    }
+
+    // Whether the browser shows the body or downloads it, e.g.
+    // `w.content_disposition(Disposition::Attachment, Some("report.csv"))`.
+    pub fn content_disposition(&mut self, kind: Disposition, filename: Option<&str>) {
+        let value = header::content_disposition(kind, filename);
+        self.header.set(header::CONTENT_DISPOSITION, &value);
+    }
+
+    // Keeps the response out of every cache, for pages that must always be fresh.
+    pub fn no_cache(&mut self) {
+        self.cache_control(&CacheControl::new().no_store());
+    }
+
+    // Sends the reader's contents as the body; without a length it is sent chunked.
+    pub fn write_stream<R>(&mut self, reader: R, length: Option<u64>)
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        self.body = Vec::new();
+        self.stream = Some(BodyStream::new(reader, length));
+        self.responded = true;
+    }
+
+    // Declares the exact body size. A stream written without a length is sent with
+    // this one; a buffered body of a different size is reported when sending.
+    pub fn content_length(&mut self, length: u64) {
+        self.declared_length = Some(length);
+    }
+
+    pub fn write_header(&mut self, status_code: HttpStatus) {
+        self.status_code = status_code;
+        self.raw_status = None;
+        self.responded = true;
+    }
+
+    // Sets the status from a bare code, e.g. one relayed from an upstream server.
+    // A code without an `HttpStatus` is sent as is and otherwise treated like the
+    // x00 code of its class (RFC 7231 6).
+    pub fn write_status_code(&mut self, code: u16) {
+        if let Some(status) = HttpStatus::from_code(code) {
+            self.write_header(status);
+            return;
+        }
+
+        match HttpStatus::from_code(code / 100 * 100).filter(|_| (100..600).contains(&code)) {
+            Some(class) => {
+                let reason = class_reason(code);
+                self.write_header(class);
+                self.raw_status = Some((code, reason.to_string()));
+            }
+            None => {
+                warn!("Invalid status code {}, sending 500 instead", code);
+                self.write_header(HttpStatus::InternalServerError);
+            }
+        }
+    }
+
+    // Like `write_status_code`, with `reason` on the status line instead of the
+    // standard phrase. A reason that isn't valid on a status line is ignored.
+    pub fn write_status_with_reason(&mut self, code: u16, reason: &str) {
+        self.write_status_code(code);
+        if self.status_code.code() / 100 != code / 100 {
+            return;
+        }
+
+        // reason-phrase = *( HTAB / SP / VCHAR / obs-text ) (RFC 7230 3.1.2)
+        let valid = reason
+            .bytes()
+            .all(|b| b == b'\t' || b == b' ' || b.is_ascii_graphic() || b >= 0x80);
+        if !valid {
+            warn!("Invalid reason phrase {:?}, sending the standard one", reason);
+            return;
+        }
+
+        self.raw_status = Some((code, reason.to_string()));
+    }
+
+    pub fn status(&self) -> &HttpStatus {
+        &self.status_code
+    }
+
+    // A Content-Length header that doesn't match the buffered body.
+    fn conflicting_length(&self) -> Option<String> {
+        let declared = self.header.get(header::CONTENT_LENGTH)?;
+        let matches = self.stream.is_some()
+            || header::parse_content_length(declared).is_ok_and(|len| len == self.body.len());
+        (!matches).then(|| declared.to_string())
+    }
+
+    // The body, as long as the response is plain buffered bytes: no stream,
+    // trailers, hijack or custom status line.
+    pub(crate) fn buffered_body(&self) -> Option<&[u8]> {
+        let plain = self.stream.is_none()
+            && self.trailers.is_none()
+            && self.hijack.is_none()
+            && self.raw_status.is_none();
+        plain.then_some(self.body.as_slice())
+    }
+
+    // Replaces the status, headers (including the default ones) and body in one call.
+    // The server's own X-Request-Id is kept unless `headers` sets one.
+    pub fn respond(&mut self, status_code: HttpStatus, headers: &[(&str, &str)], body: &[u8]) {
+        let request_id = self.header.get(header::X_REQUEST_ID).map(str::to_string);
+        self.header.headers.clear();
+        if let Some(id) = &request_id {
+            self.header.set(header::X_REQUEST_ID, id);
+        }
+        for (key, value) in headers {
+            self.header.set(key, value);
+        }
+
+        self.status_code = status_code;
+        self.raw_status = None;
+        self.declared_length = None;
+        self.trailers = None;
+        self.hijack = None;
+        // `write` also drops any stream.
+        self.write(body);
+    }
+
+    // Sends the fields returned by `f` after the last chunk of a stream written
+    // without a length, e.g. a checksum of the body. `names` are announced up front
+    // in the Trailer header.
+    pub fn trailers<F>(&mut self, names: &[&str], f: F)
+    where
+        F: FnOnce() -> Vec<(String, String)> + Send + 'static,
+    {
+        let names = names.iter().map(|name| name.to_string()).collect();
+        self.trailers = Some((names, Box::new(f)));
+    }
+
+    // Takes over the connection once the response is sent, e.g. after a 101 for a
+    // WebSocket upgrade. The Connection header is left as the handler set it and no
+    // further requests are read.
+    pub fn hijack<F, Fut>(&mut self, f: F)
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hijack = Some(Box::new(move |upgraded| Box::pin(f(upgraded))));
+    }
+
+    fn into_response(self) -> HttpResponse {
+        let mut stream = self.stream;
+
+        if let Some(declared) = self.declared_length {
+            match stream.as_mut() {
+                Some(body) => match body.length {
+                    Some(length) if length != declared => warn!(
+                        "Declared Content-Length {} but the stream was given length {}",
+                        declared, length
+                    ),
+                    Some(_) => {}
+                    None => body.length = Some(declared),
+                },
+                None if declared != self.body.len() as u64 => warn!(
+                    "Declared Content-Length {} but wrote {} bytes, sending the real length",
+                    declared,
+                    self.body.len()
+                ),
+                None => {}
+            }
+        }
+
+        let mut headers = self.header.headers;
+        if let Some((names, trailers)) = self.trailers {
+            match stream.as_mut() {
+                Some(body) if body.length.is_none() => {
+                    headers.insert(header::TRAILER.to_string(), names.join(", "));
+                    body.trailers = Some(trailers);
+                }
+                _ => warn!("Trailers can only be sent after a chunked body, dropping them"),
+            }
+        }
+
+        HttpResponse {
+            headers,
+            status_code: self.status_code,
+            raw_status: self.raw_status,
+            body: self.body,
+            stream,
+        }
+    }
+
+    pub fn redirect(&mut self, location: &str, status_code: HttpStatus) {
+        self.header.set(header::LOCATION, location);
+        self.write_header(status_code);
+    }
+
+    // Redirects like `redirect`, carrying the request's query string over to
+    // `location`. A query already on `location` is kept and the original appended;
+    // a fragment stays at the end.
+    pub fn redirect_with_query(
+        &mut self,
+        r: &HttpRequest,
+        location: &str,
+        status_code: HttpStatus,
+    ) {
+        let Some(query) = r.query().filter(|query| !query.is_empty()) else {
+            self.redirect(location, status_code);
+            return;
+        };
+
+        let (base, fragment) = match location.split_once('#') {
+            Some((base, fragment)) => (base, format!("#{}", fragment)),
+            None => (location, String::new()),
+        };
+        let separator = match base.find('?') {
+            None => "?",
+            // "/new?" or "/new?a=1&" already end in a separator.
+            Some(start) if start == base.len() - 1 || base.ends_with('&') => "",
+            Some(_) => "&",
+        };
+        self.redirect(&format!("{}{}{}{}", base, separator, query, fragment), status_code);
+    }
+
+    pub fn require_basic_auth(&mut self, realm: &str) {
+        let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+        self.header.set(header::WWW_AUTHENTICATE, &format!("Basic realm=\"{}\"", realm));
+        self.write_header(HttpStatus::Unauthorized);
+    }
+}
+
+#[cfg(feature = "tls")]
+pub async fn init_tls(router: Router, addrs: &str, cert_path: &str, key_path: &str) {
+    init_tls_with_shutdown(router, addrs, cert_path, key_path, pending()).await;
+}
+
+#[cfg(feature = "tls")]
+pub async fn init_tls_with_shutdown<F>(
+    router: Router,
+    addrs: &str,
+    cert_path: &str,
+    key_path: &str,
+    signal: F,
+) where
+    F: Future<Output = ()>,
+{
+    let tls_config = configure_tls(cert_path, key_path, &router.config.tls);
+    init_tls_with_config(router, addrs, tls_config, signal).await;
+}
+
+#[cfg(feature = "tls")]
+pub async fn init_sni_tls(router: Router, addrs: &str, resolver: SniResolver) {
+    let tls_config = configure_sni_tls(resolver, &router.config.tls);
+    init_tls_with_config(router, addrs, tls_config, pending()).await;
+}
+
+#[cfg(feature = "tls")]
+pub async fn init_tls_with_config<F>(
+    router: Router,
+    addrs: &str,
+    tls_config: Arc<ServerConfig>,
+    signal: F,
+) where
+    F: Future<Output = ()>,
+{
+    let listener = bind(addrs, &router.config)
+        .await
+        .expect("Failed to bind address");
+
+    let tls_acceptor = Some(TlsAcceptor::from(tls_config));
+
+    serve(listener, router, tls_acceptor, signal).await;
+}
+
+pub async fn init(router: Router, addrs: &str) {
+    init_with_shutdown(router, addrs, pending()).await;
+}
+
+pub async fn init_with_shutdown<F>(router: Router, addrs: &str, signal: F)
+where
+    F: Future<Output = ()>,
+{
+    let listener = bind(addrs, &router.config)
+        .await
+        .expect("Failed to bind address");
+
+    serve(listener, router, None, signal).await;
+}
+
+// Binds like `TcpListener::bind`, trying each address `addrs` resolves to, except
+// that IPv6 listeners get IPV6_V6ONLY from `config.ipv6_only` instead of the
+// platform default.
+async fn bind(addrs: &str, config: &Config) -> std::io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(addrs).await? {
+        match bind_addr(addr, config.ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "No addresses to bind to")
+    }))
+}
+
+fn bind_addr(addr: SocketAddr, ipv6_only: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Same as tokio's own bind: allows rebinding while old connections linger.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+async fn serve<F>(
+    listener: TcpListener,
+    router: Router,
+    tls_acceptor: Option<TlsAcceptor>,
+    signal: F,
+) where
+    F: Future<Output = ()>,
+{
+    let mut tasks = JoinSet::new();
+    let mut backoff: Option<Duration> = None;
+    // Flipped once shutdown starts, so open connections stop keeping themselves alive.
+    let (closing, shutdown) = watch::channel(false);
+    tokio::pin!(signal);
+
+    loop {
+        tokio::select! {
+            _ = &mut signal => {
+                info!("Shutdown signal received, no longer accepting connections");
+                break;
+            }
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+            accepted = listener.accept() => match accepted {
+                Ok((stream, socket)) => {
+                    backoff = None;
+                    let at_limit = router
+                        .config
+                        .max_connections
+                        .is_some_and(|max| router.stats.active() >= max as u64);
+                    if at_limit {
+                        info!("Connection from {} rejected: connection limit reached", socket);
+                        router.stats.reject();
+                        continue;
+                    }
+                    // Behind PROXY protocol the client is only known once the header is
+                    // read, so it is admitted then instead.
+                    let admitted = if router.config.proxy_protocol {
+                        None
+                    } else {
+                        match admit(&router, socket) {
+                            Some(active) => Some(active),
+                            None => continue,
+                        }
+                    };
+
+                    let tls_acceptor = tls_acceptor.clone();
+                    let router_clone = router.clone();
+                    let shutdown = shutdown.clone();
+
+                    let task = async move {
+                        let traffic = Arc::new(Traffic::default());
+                        let _log = ConnectionLog::open(socket, traffic.clone());
+                        let mut stream = CountingStream::new(stream, traffic);
+
+                        // The PROXY header comes first, before any TLS handshake.
+                        let socket = if router_clone.config.proxy_protocol {
+                            let header = proxy::read_header(&mut stream, socket);
+                            match tokio::time::timeout(router_clone.config.header_timeout, header)
+                                .await
+                            {
+                                Ok(Ok(client)) => client,
+                                Ok(Err(e)) => {
+                                    info!("Connection from {} dropped: {}", socket, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    info!("Connection from {} dropped: no PROXY header", socket);
+                                    return;
+                                }
+                            }
+                        } else {
+                            socket
+                        };
+                        let admitted = admitted.or_else(|| admit(&router_clone, socket));
+                        let Some(_active) = admitted else {
+                            return;
+                        };
+
+                        if let Some(acceptor) = tls_acceptor {
+                            accept_tls(acceptor, stream, socket, &router_clone, shutdown).await;
+                        } else {
+                            info!("Connection accepted from {}", socket);
+                            handle_connection(stream, socket, &router_clone, false, shutdown)
+                                .await;
+                        }
+                    };
+                    #[cfg(feature = "tracing")]
+                    let task = task.instrument(info_span!("connection", peer = %socket));
+                    tasks.spawn(task);
+                }
+                Err(e) => {
+                    // Errors like EMFILE persist for a while; retrying at once would spin.
+                    let delay = backoff.map_or(router.config.accept_backoff_min, |delay| {
+                        (delay * 2).min(router.config.accept_backoff_max)
+                    });
+                    error!("Failed to accept connection: {}, retrying in {:?}", e, delay);
+                    backoff = Some(delay);
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = &mut signal => {
+                            info!("Shutdown signal received, no longer accepting connections");
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // New connections are refused from here on rather than left in the backlog.
+    drop(listener);
+    let _ = closing.send(true);
+    drain(tasks, router.config.shutdown_grace).await;
+}
+
+// Applies the per-IP limit and the accept hook to the client at `socket`, and
+// counts the connection as open if both let it through.
+fn admit(router: &Router, socket: SocketAddr) -> Option<ActiveConnection> {
+    let ip_at_limit = router
+        .config
+        .max_connections_per_ip
+        .is_some_and(|max| router.stats.active_from(socket.ip()) >= max);
+    if ip_at_limit {
+        info!("Connection from {} rejected: per-IP limit reached", socket);
+        router.stats.reject();
+        return None;
+    }
+    if let Some(on_accept) = &router.config.on_accept {
+        if let Err(e) = on_accept(socket) {
+            info!("Connection from {} rejected: {}", socket, e);
+            router.stats.reject();
+            return None;
+        }
+    }
+
+    Some(router.stats.open(socket.ip()))
+}
+
+#[cfg(feature = "tls")]
+async fn accept_tls<T>(
+    acceptor: TlsAcceptor,
+    stream: T,
+    socket: SocketAddr,
+    router: &Router,
+    shutdown: watch::Receiver<bool>,
+) where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match acceptor.accept(stream).await {
+        Ok(stream) => {
+            info!("TLS connection accepted from {}", socket);
+            handle_connection(stream, socket, router, true, shutdown).await;
+        }
+        Err(e) => {
+            error!("Failed to accept TLS connection from {}: {}", socket, e);
+        }
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+async fn accept_tls<T>(
+    acceptor: TlsAcceptor,
+    _: T,
+    _: SocketAddr,
+    _: &Router,
+    _: watch::Receiver<bool>,
+) {
+    match acceptor {}
+}
+
+async fn drain(mut tasks: JoinSet<()>, grace: Duration) {
+    if tasks.is_empty() {
+        return;
+    }
+
+    info!("Waiting up to {:?} for {} connection(s) to finish", grace, tasks.len());
+
+    let finished = tokio::time::timeout(grace, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+
+    if finished.is_err() {
+        warn!("Grace period elapsed, aborting {} connection(s)", tasks.len());
+        tasks.shutdown().await;
+    }
+}
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// `shutdown` turns true once the server stops accepting; the request being served
+// then gets "Connection: close", and an idle connection is closed at once.
+async fn handle_connection<T>(
+    mut stream: T,
+    socket: SocketAddr,
+    router: &Router,
+    secure: bool,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    T: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
+{
+    let mut buffer = DynamicBuffer::new(&mut stream);
+    let mut served = 0;
+    let mut hijack = None;
+
+    while respond(&mut buffer, socket, router, secure, served > 0, &mut hijack, &mut shutdown).await
+    {
+        served += 1;
+    }
+
+    if let Some(hijack) = hijack {
+        let buffered = std::mem::take(&mut buffer.headers);
+        debug!("Connection to {} hijacked by its handler", socket);
+        hijack(Upgraded::new(Box::new(stream), buffered)).await;
+        return;
+    }
+
+    // For TLS streams this is what sends close_notify before the socket is dropped,
+    // on every path out of the loop above, errors included. A peer that stopped
+    // reading can't hold the task open past CLOSE_TIMEOUT.
+    match tokio::time::timeout(CLOSE_TIMEOUT, stream.shutdown()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Failed to shut down connection to {}: {}", socket, e),
+        Err(_) => debug!("Timed out shutting down connection to {}", socket),
+    }
+}
+
+// The first, client-facing entry of X-Forwarded-Proto.
+fn forwarded_https(req: &HttpRequest) -> bool {
+    req.header(header::X_FORWARDED_PROTO)
+        .and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+// Serves one request from the buffer and reports whether the connection can be reused.
+async fn respond<T>(
+    buffer: &mut DynamicBuffer<T>,
+    socket: SocketAddr,
+    router: &Router,
+    secure: bool,
+    idle: bool,
+    hijack: &mut Option<Hijack>,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    // An idle keep-alive connection gets `keep_alive_timeout` for its next request,
+    // a fresh one `header_timeout` to send its first.
+    let limit = if idle {
+        router.config.keep_alive_timeout
+    } else {
+        router.config.header_timeout
+    };
+    // During shutdown an idle connection stops waiting, unless part of its next
+    // request has already arrived; that one is still read and served.
+    let mut read = None;
+    if idle {
+        tokio::select! {
+            result = tokio::time::timeout(limit, buffer.read_headers(&router.config)) => {
+                read = Some(result);
+            }
+            _ = shutdown.wait_for(|closing| *closing) => {}
+        }
+        if read.is_none() && buffer.headers.is_empty() {
+            debug!("Closing idle connection from {} for shutdown", socket);
+            return false;
+        }
+    }
+    let read = match read {
+        Some(read) => read,
+        None => tokio::time::timeout(limit, buffer.read_headers(&router.config)).await,
+    };
+    let read = match read {
+        Ok(read) => read,
+        Err(_) if buffer.headers.is_empty() => {
+            debug!("Timed out waiting for a request from {}", socket);
+            return false;
+        }
+        Err(_) => Err(RequestError::new(
+            HttpStatus::RequestTimeout,
+            "Timed out reading request headers",
+        )),
+    };
+
+    match read {
+        Ok(true) => {}
+        Ok(false) => return false,
+        Err(e) => {
+            error!("Failed to read request headers: {}", e);
+            send_error(&mut buffer.stream, e, &router.config, None).await;
+            return false;
+        }
+    }
+
+    // `max_request_time` counts from the first byte. Reading the body and writing the
+    // response stop at the deadline here; `dispatch` cuts the handler off.
+    let deadline = router
+        .config
+        .max_request_time
+        .map(|max| buffer.started.unwrap_or_else(Instant::now) + max);
+
+    // Kept so an error after the head was parsed, like a 413, can still follow Accept.
+    let mut accept = None;
+    let parsed = async {
+        let mut req = HttpRequest::parse_head(&buffer.headers, socket, &router.config)?;
+        accept = req.header(header::ACCEPT).map(str::to_string);
+        req.secure = secure || (router.config.trust_forwarded_proto && forwarded_https(&req));
+        if let Some(rewrite) = &router.config.rewrite {
+            rewrite(&mut req);
+        }
+        let max_body = router.body_limit(&req.path);
+        // Checked before 100 Continue, so a client isn't invited to send a body that
+        // would be refused anyway.
+        req.check_body_size(max_body)?;
+        if req.expects_continue() {
+            buffer.send_continue(router.config.continue_timeout).await?;
+        }
+
+        let streamed = req
+            .content_length
+            .filter(|length| *length > 0 && router.streamed.contains(&req.path));
+        if streamed.is_none() {
+            req.read_body(buffer, &router.config, max_body).await?;
+        }
+        Ok::<_, RequestError>((req, streamed))
+    };
+    let parsed = within(deadline, parsed).await.unwrap_or_else(|| {
+        Err(RequestError::new(
+            HttpStatus::ServiceUnavailable,
+            "Request took too long",
+        ))
+    });
+    let (mut req, streamed) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            error!("Failed to parse request: {}", e);
+            send_error(&mut buffer.stream, e, &router.config, accept.as_deref()).await;
+            return false;
+        }
+    };
+
+    let keep_alive = router.config.keep_alive && wants_keep_alive(&req);
+    req.deadline = deadline;
+
+    if router.config.log_bodies {
+        debug!(
+            "Request body from {}: {}",
+            socket,
+            body_preview(&req.body, router.config.log_body_limit)
+        );
+    }
+
+    let head = req.method == Method::Head.as_str();
+    let mut writer = router.new_writer();
+    let mut sender = None;
+    if streamed.is_some() {
+        let (chunk_sender, chunks) = BodyChunks::channel();
+        req.body_chunks = Some(chunks);
+        sender = Some(chunk_sender);
+    }
+
+    let id = router.config.request_id.then(|| request_id::from_headers(&mut req.headers));
+    if let Some(id) = &id {
+        writer.header().set(header::X_REQUEST_ID, id);
+    }
+    #[cfg(feature = "tracing")]
+    let span = info_span!(
+        "request",
+        method = %req.method,
+        path = %req.path,
+        status = tracing::field::Empty,
+    );
+    let handled = async {
+        match id {
+            Some(id) => request_id::scope(id, dispatch(router, &mut writer, req)).await,
+            None => dispatch(router, &mut writer, req).await,
+        }
+    };
+    #[cfg(feature = "tracing")]
+    let handled = handled.instrument(span.clone());
+
+    // The connection can only be reused if the streamed body was read to the end.
+    let body_read = match (sender, streamed) {
+        (Some(sender), Some(length)) => {
+            tokio::pin!(handled);
+            let pump = pump_body(buffer, sender, length, router.config.body_timeout);
+            tokio::pin!(pump);
+            tokio::select! {
+                biased;
+                body_read = &mut pump => {
+                    handled.await;
+                    body_read
+                }
+                _ = &mut handled => false,
+            }
+        }
+        _ => {
+            handled.await;
+            true
+        }
+    };
+    let keep_alive = keep_alive && body_read && !*shutdown.borrow();
+
+    let hijacked = writer.hijack.take();
+    let mut response = writer.into_response();
+    #[cfg(feature = "tracing")]
+    span.record("status", response.status_code.code());
+    add_default_charset(&mut response.headers, &router.config.default_charset);
+    if let Some(finalizer) = &router.config.finalizer {
+        finalizer(&mut response);
+    }
+
+    // A handler can force the connection closed by setting "Connection: close".
+    let handler_close = has_token(header::get(&response.headers, header::CONNECTION), "close");
+    let keep_alive = keep_alive && !handler_close;
+    if hijacked.is_none() {
+        response.headers.retain(|k, _| !k.eq_ignore_ascii_case(header::CONNECTION));
+        response.headers.insert(
+            header::CONNECTION.to_string(),
+            if keep_alive { "keep-alive" } else { "close" }.to_string(),
+        );
+    }
+
+    if router.config.log_bodies {
+        debug!(
+            "Response body to {}: {}",
+            socket,
+            body_preview(&response.body, router.config.log_body_limit)
+        );
+    }
+
+    let upgrade = hijacked.is_some();
+    let write = write_response(&mut buffer.stream, response, !head, upgrade);
+    let Some(sent) = within(deadline, write).await else {
+        warn!("Timed out sending the response to {}", socket);
+        return false;
+    };
+    if sent && hijacked.is_some() {
+        *hijack = hijacked;
+        return false;
+    }
+
+    sent && keep_alive
+}
+
+// Headers that describe the body rather than the response as a whole.
+fn is_entity_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("content-")
+        || matches!(name.as_str(), "transfer-encoding" | "etag" | "last-modified" | "expires")
+}
+
+// Text types without a charset get the configured one, so browsers don't have to guess.
+fn add_default_charset(headers: &mut HashMap<String, String>, charset: &str) {
+    let Some((_, value)) = headers
+        .iter_mut()
+        .find(|(k, _)| k.eq_ignore_ascii_case(header::CONTENT_TYPE))
+    else {
+        return;
+    };
+
+    let is_text = header::parse_content_type(value).is_some_and(|content_type| {
+        content_type.media_type.starts_with("text/") && content_type.charset.is_none()
+    });
+    if is_text {
+        value.push_str("; charset=");
+        value.push_str(charset);
+    }
+}
+
+// HTTP/1.1 connections persist unless either side says close; HTTP/1.0 ones only
+// persist when the client asks for keep-alive.
+fn wants_keep_alive(req: &HttpRequest) -> bool {
+    let connection = req.header(header::CONNECTION);
+    if has_token(connection, "close") {
+        return false;
+    }
+
+    match req.version.as_str() {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => has_token(connection, "keep-alive"),
+        _ => false,
+    }
+}
+
+// "/api//users///1" becomes "/api/users/1".
+fn collapse_slashes(path: &str) -> String {
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && collapsed.ends_with('/')) {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+async fn dispatch(router: &Router, writer: &mut Writer, mut req: HttpRequest) {
+    let method = req.method_enum();
+    let accept = req.header(header::ACCEPT).map(str::to_string);
+    let accept = accept.as_deref();
+    let auto_options = method == Method::Options && router.config.auto_options;
+    if auto_options && req.path == "*" {
+        writer.header().set(header::ALLOW, &join_methods(&router.server_methods()));
+        writer.status_code = HttpStatus::OK;
+        return;
+    }
+
+    if let Some(https_port) = router.config.https_redirect {
+        redirect_to_https(writer, &req, https_port);
+        return;
+    }
+
+    // Paths with method-specific routes answer OPTIONS themselves unless one of the
+    // routes is for OPTIONS.
+    let allowed = router.allowed_methods(&req.path);
+    let explicit_options = router.get_method_handler(&Method::Options, &req.path).is_some();
+    if auto_options && !allowed.is_empty() && !explicit_options {
+        writer.header().set(header::ALLOW, &join_methods(&allowed));
+        writer.status_code = HttpStatus::OK;
+        return;
+    }
+
+    if let Some(handler) = router.find_handler(req.host.as_deref(), &method, &req.path) {
+        req.matched_pattern = Some(router.pattern_for(&req.path));
+        let handler = router.wrap(&req.path, handler.clone());
+        // The earlier of the route's timeout and the server's `max_request_time`.
+        let route_deadline = router
+            .timeouts
+            .get(&req.path)
+            .map(|limit| req.received_at + *limit);
+        req.deadline = match (req.deadline, route_deadline) {
+            (Some(total), Some(route)) => Some(total.min(route)),
+            (total, route) => total.or(route),
+        };
+        let path = req.path.clone();
+        let dev_page = router.config.dev_mode.then(|| dev_page(&req));
+        // A streamed body is read while the handler runs, so it can't run on this task.
+        if req.deadline.is_some() || req.body_chunks.is_some() {
+            run_blocking(router, writer, handler, req).await;
+        } else {
+            handler(writer, req);
+        }
+
+        if router.config.strict_responses && !writer.responded {
+            error!("Handler for path {} wrote no response", path);
+            write_error(router, writer, accept, HttpStatus::InternalServerError);
+        } else if router.config.strict_responses && method != Method::Head {
+            if let Some(declared) = writer.conflicting_length() {
+                error!(
+                    "Handler for path {} set Content-Length {} but wrote {} bytes",
+                    path,
+                    declared,
+                    writer.body.len()
+                );
+                writer.header().del(header::CONTENT_LENGTH);
+                write_error(router, writer, accept, HttpStatus::InternalServerError);
+            }
+        }
+        if let Some(page) = dev_page.filter(|_| writer.wrote_nothing()) {
+            writer.html(&page);
+        }
+        return;
+    }
+
+    if !allowed.is_empty() {
+        warn!("Method {} not allowed for path: {}", req.method, req.path);
+        writer.header().set(header::ALLOW, &join_methods(&allowed));
+        write_error(router, writer, accept, HttpStatus::MethodNotAllowed);
+        return;
+    }
+
+    warn!("No handler found for path: {}", req.path);
+    write_error(router, writer, accept, HttpStatus::NotFound);
+}
+
+// The `dev_mode` page for a handler that wrote nothing: the request line and
+// headers, escaped.
+fn dev_page(req: &HttpRequest) -> String {
+    let mut headers: Vec<_> = req.headers.iter().collect();
+    headers.sort();
+    let mut rows = String::new();
+    for (name, value) in headers {
+        rows.push_str(&format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            files::escape_html(name),
+            files::escape_html(value)
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html><head><title>No response</title></head><body>\n\
+         <h1>The handler for {0} wrote no response</h1>\n<p><code>{1} {0} {2}</code></p>\n\
+         <table>\n{3}</table>\n</body></html>\n",
+        files::escape_html(&req.path),
+        files::escape_html(&req.method),
+        files::escape_html(&req.version),
+        rows
+    )
+}
+
+// Gives an error response the server generated itself a body from the
+// `error_renderer`, with the status's reason phrase as the message.
+fn write_error(router: &Router, writer: &mut Writer, accept: Option<&str>, status: HttpStatus) {
+    let reason = status.reason();
+    let e = RequestError::new(status, reason);
+    let (content_type, body) = (router.config.error_renderer)(&e, accept);
+    writer.header().set(header::CONTENT_TYPE, &content_type);
+    writer.status_code = e.status;
+    writer.body = body;
+}
+
+fn join_methods(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(|method| method.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Runs `f` until `deadline`, or to completion without one.
+async fn within<F: Future>(deadline: Option<Instant>, f: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), f).await.ok(),
+        None => Some(f.await),
+    }
+}
+
+// Handlers are synchronous, so a timed out handler keeps its blocking thread until
+// it returns; only the response is abandoned.
+async fn run_blocking(
+    router: &Router,
+    writer: &mut Writer,
+    handler: Handler,
+    req: HttpRequest,
+) {
+    let path = req.path.clone();
+    let received_at = req.received_at;
+    let deadline = req.deadline;
+    let accept = req.header(header::ACCEPT).map(str::to_string);
+    let mut task_writer = std::mem::replace(writer, Writer::new());
+    // The handler's writer is lost if it times out or panics; the error response still
+    // gets the headers set before it ran, like X-Request-Id and the default headers.
+    writer.header.headers = task_writer.header.headers.clone();
+    let id = request_id::current();
+    #[cfg(feature = "tracing")]
+    let span = tracing::Span::current();
+    let task = tokio::task::spawn_blocking(move || {
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        // Task-locals don't follow the handler onto the blocking thread.
+        match id {
+            Some(id) => request_id::sync_scope(id, || handler(&mut task_writer, req)),
+            None => handler(&mut task_writer, req),
+        }
+        task_writer
+    });
+
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), task).await,
+        None => Ok(task.await),
+    };
+
+    match result {
+        Ok(Ok(task_writer)) => *writer = task_writer,
+        Ok(Err(e)) => {
+            error!("Handler for path {} failed: {}", path, e);
+            write_error(router, writer, accept.as_deref(), HttpStatus::InternalServerError);
+        }
+        Err(_) => {
+            warn!("Handler for path {} timed out after {:?}", path, received_at.elapsed());
+            write_error(router, writer, accept.as_deref(), HttpStatus::GatewayTimeout);
+        }
+    }
+}
+
+// Feeds a streamed body to its handler as it arrives. Returns whether all of it was
+// read; a handler that stops reading early leaves the rest on the connection. Like
+// a buffered body, each read must bring bytes within `timeout`, or the handler's
+// next read fails.
+async fn pump_body<T>(
+    buffer: &mut DynamicBuffer<T>,
+    sender: mpsc::Sender<Result<Vec<u8>, String>>,
+    content_length: usize,
+    timeout: Duration,
+) -> bool
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let buffered = buffer.body.len().min(content_length);
+    let first = buffer.body[..buffered].to_vec();
+    buffer.finish_request(buffered);
+
+    let mut remaining = content_length - buffered;
+    if !first.is_empty() && sender.send(Ok(first)).await.is_err() {
+        return remaining == 0;
+    }
+
+    while remaining > 0 {
+        let mut chunk = vec![0; remaining.min(8 * 1024)];
+        let read = tokio::time::timeout(timeout, buffer.stream.read(&mut chunk)).await;
+        let bytes_read = match read {
+            Ok(Ok(0)) => {
+                let _ = sender.send(Err("Connection closed before reading full body".into())).await;
+                return false;
+            }
+            Ok(Ok(bytes_read)) => bytes_read,
+            Ok(Err(e)) => {
+                let _ = sender.send(Err(e.to_string())).await;
+                return false;
+            }
+            Err(_) => {
+                debug!("Timed out reading streamed request body");
+                let _ = sender.send(Err("Timed out reading request body".into())).await;
+                return false;
+            }
+        };
+
+        chunk.truncate(bytes_read);
+        remaining -= bytes_read;
+        if sender.send(Ok(chunk)).await.is_err() {
+            return remaining == 0;
+        }
+    }
+
+    true
+}
+
+async fn send_error<T>(stream: T, e: RequestError, config: &Config, accept: Option<&str>)
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let (content_type, body) = (config.error_renderer)(&e, accept);
+    let mut response = HttpResponse {
+        status_code: e.status,
+        raw_status: None,
+        headers: config.default_headers.clone(),
+        body,
+        stream: None,
+    };
+    response
+        .headers
+        .insert(header::CONTENT_TYPE.to_string(), content_type);
+    add_default_charset(&mut response.headers, &config.default_charset);
+    response
+        .headers
+        .insert(header::CONNECTION.to_string(), "close".to_string());
+
+    send_response(stream, response).await;
+}
 
-        Ok(HttpRequest {
-            method,
-            body,
-            headers,
-            path,
-            socket,
-        })
+fn body_preview(body: &[u8], limit: usize) -> String {
+    if body.len() <= limit {
+        return String::from_utf8_lossy(body).to_string();
     }
+
+    format!(
+        "{}... ({} bytes total)",
+        String::from_utf8_lossy(&body[..limit]),
+        body.len()
+    )
 }
 
-pub struct HttpResponse {
-    pub status_code: HttpStatus,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+// Serializes a response onto any stream, for integrations that bypass the Router.
+// Returns false if the response could not be sent completely.
+pub async fn send_response<T>(stream: T, response: HttpResponse) -> bool
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    write_response(stream, response, true, false).await
 }
 
-pub type Handler = Arc<dyn Fn(&mut Writer, HttpRequest) + Send + Sync>;
+// Without the body, the headers still describe it, as a response to HEAD requires.
+// For an upgrade, where the connection is hijacked afterwards, 101 is final.
+async fn write_response<T>(
+    mut stream: T,
+    mut response: HttpResponse,
+    with_body: bool,
+    upgrade: bool,
+) -> bool
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let switching = upgrade && response.status_code == HttpStatus::SwitchingProtocols;
 
-pub struct Router {
-    routes: HashMap<String, Handler>,
-}
+    // A 1xx status can only precede a final response, never replace it.
+    if response.status_code.is_informational() && !switching {
+        error!(
+            "Handler left informational status {} as the final response, sending 500 instead",
+            response.status_code.to_string()
+        );
+        response.status_code = HttpStatus::InternalServerError;
+        response.raw_status = None;
+        response.body = Vec::new();
+        response.stream = None;
+        // They describe the handler's body, which is no longer sent.
+        response.headers.retain(|k, _| !is_entity_header(k));
+    }
 
-impl Router {
-    pub fn new() -> Self {
-        Router {
-            routes: HashMap::new(),
+    // 204 and 304 responses never carry a body, so they must not describe one either.
+    if matches!(response.status_code, HttpStatus::NoContent | HttpStatus::NotModified) {
+        if !response.body.is_empty() || response.stream.is_some() {
+            warn!(
+                "Dropping body written for a {} response",
+                response.status_code.to_string()
+            );
         }
-    }
 
-    pub fn handle_func(&mut self, path: &str, handler: Handler) {
-        self.routes.insert(path.to_string(), handler);
+        response.body = Vec::new();
+        response.stream = None;
+        response.headers.retain(|k, _| {
+            !k.eq_ignore_ascii_case(header::CONTENT_LENGTH)
+                && !k.eq_ignore_ascii_case(header::CONTENT_TYPE)
+                && !k.eq_ignore_ascii_case(header::TRANSFER_ENCODING)
+        });
     }
 
-    pub fn get_handler(&self, path: &str) -> Option<&Handler> {
-        self.routes.get(path)
+    if let Some(body) = response.stream.take() {
+        return send_stream_response(&mut stream, response, body, with_body).await;
     }
-}
 
-impl Clone for Router {
-    fn clone(&self) -> Self {
-        Router {
-            routes: self.routes.clone(),
+    let bodiless = matches!(response.status_code, HttpStatus::NoContent | HttpStatus::NotModified);
+    let declared = header::get(&response.headers, header::CONTENT_LENGTH)
+        .map(|value| header::parse_content_length(value).ok());
+    // A HEAD handler may describe the body it would send without writing it.
+    let head_length = !with_body && response.body.is_empty() && matches!(declared, Some(Some(_)));
+    if !bodiless && !switching && !head_length {
+        // A wrong length would desync the framing of the next keep-alive request.
+        if declared.is_some_and(|declared| declared != Some(response.body.len())) {
+            warn!(
+                "Handler set Content-Length {} but wrote {} bytes, sending the real length",
+                header::get(&response.headers, header::CONTENT_LENGTH).unwrap_or_default(),
+                response.body.len()
+            );
         }
+        response.headers.retain(|k, _| !k.eq_ignore_ascii_case(header::CONTENT_LENGTH));
+        response
+            .headers
+            .insert(header::CONTENT_LENGTH.to_string(), response.body.len().to_string());
+    }
+
+    let body: &[u8] = if with_body { &response.body } else { &[] };
+    let bytes = encode_response(&response, body);
+
+    if let Err(e) = stream.write_all(&bytes).await {
+        error!("Failed to send response: {}", e);
+        return false;
+    }
+
+    if let Err(e) = stream.flush().await {
+        error!("Failed to flush stream: {}", e);
+        return false;
     }
+
+    true
 }
 
-pub struct Header {
-    headers: HashMap<String, String>,
+// The status line and headers followed by `body`, in one buffer sized up front, so
+// a small response goes out in a single write with no intermediate Strings.
+pub fn encode_response(response: &HttpResponse, body: &[u8]) -> Vec<u8> {
+    use std::io::Write as _;
+
+    let status = response.status_code.to_string();
+    // A raw status is a three-digit code, a space and the reason.
+    let status_len = response
+        .raw_status
+        .as_ref()
+        .map_or(status.len(), |(_, reason)| reason.len() + 4);
+    let headers_len: usize = response
+        .headers
+        .iter()
+        .map(|(k, v)| k.len() + v.len() + 4)
+        .sum();
+
+    let mut bytes = Vec::with_capacity(11 + status_len + headers_len + 2 + body.len());
+    bytes.extend_from_slice(b"HTTP/1.1 ");
+    match &response.raw_status {
+        Some((code, reason)) => {
+            let _ = write!(bytes, "{} {}", code, reason);
+        }
+        None => bytes.extend_from_slice(status.as_bytes()),
+    }
+    bytes.extend_from_slice(b"\r\n");
+    for (key, value) in &response.headers {
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(b": ");
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
+    }
+    bytes.extend_from_slice(b"\r\n");
+    bytes.extend_from_slice(body);
+    bytes
 }
 
-impl Header {
-    fn new() -> Self {
-        Header {
-            headers: HashMap::new(),
+async fn send_stream_response<T>(
+    mut stream: T,
+    mut response: HttpResponse,
+    mut body: BodyStream,
+    with_body: bool,
+) -> bool
+where
+    T: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    // The stream's own length, or chunked framing, replaces whatever the handler set.
+    if let Some(declared) = header::get(&response.headers, header::CONTENT_LENGTH) {
+        if body.length.map(|length| length.to_string()).as_deref() != Some(declared.trim()) {
+            warn!("Dropping Content-Length {} set by the handler for a stream", declared);
         }
+        response.headers.retain(|k, _| !k.eq_ignore_ascii_case(header::CONTENT_LENGTH));
     }
 
-    pub fn get(&self, key: &str) {
-        self.headers.get(key);
+    match body.length {
+        Some(length) => {
+            response
+                .headers
+                .insert(header::CONTENT_LENGTH.to_string(), length.to_string());
+        }
+        None => {
+            response
+                .headers
+                .insert(header::TRANSFER_ENCODING.to_string(), "chunked".to_string());
+        }
     }
 
-    pub fn set(&mut self, key: &str, value: &str) {
-        self.add(key, value);
+    let head = encode_response(&response, &[]);
+
+    if let Err(e) = stream.write_all(&head).await {
+        error!("Failed to send response: {}", e);
+        return false;
     }
 
-    pub fn del(&mut self, key: &str) {
-        self.headers.remove(key);
+    let result = match body.length {
+        _ if !with_body => Ok(()),
+        Some(length) => copy_exact(&mut body.reader, &mut stream, length).await,
+        None => copy_chunked(&mut body.reader, &mut stream, body.trailers.take()).await,
+    };
+
+    if let Err(e) = result {
+        error!("Failed to send response body: {}", e);
+        return false;
     }
 
-    fn add(&mut self, key: &str, value: &str) {
-        self.headers.insert(key.to_string(), value.to_string());
+    if let Err(e) = stream.flush().await {
+        error!("Failed to flush stream: {}", e);
+        return false;
     }
-}
 
-pub struct Writer {
-    header: Header,
-    status_code: HttpStatus,
-    body: Vec<u8>,
+    true
 }
 
-impl Writer {
-    pub fn header(&mut self) -> &mut Header {
-        &mut self.header
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, length: u64) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let copied = tokio::io::copy(&mut reader.take(length), writer).await?;
+    if copied < length {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("body stream ended after {} of {} bytes", copied, length),
+        ));
     }
 
-    pub fn write(&mut self, data: &[u8]) {
-        self.body = data.to_vec();
+    // Anything past the declared length would desync the connection, so it is dropped.
+    // Only data that is already available is checked, a live pipe is not waited on.
+    let mut extra = [0; 1];
+    if let Ok(Ok(1..)) = tokio::time::timeout(Duration::ZERO, reader.read(&mut extra)).await {
+        warn!("Body stream has more than the declared {} bytes, truncating", length);
     }
 
-    pub fn write_header(&mut self, status_code: HttpStatus) {
-        self.status_code = status_code;
-    }
+    Ok(())
 }
 
-pub async fn init_tls(router: Router, addrs: &str, cert_path: &str, key_path: &str) {
-    let listener = tokio::net::TcpListener::bind(addrs)
-        .await
-        .expect("Failed to bind address");
-
-    let tls_acceptor = Some(TlsAcceptor::from(configure_tls(cert_path, key_path)));
+async fn copy_chunked<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    trailers: Option<TrailerFn>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = vec![0; 8192];
 
     loop {
-        match listener.accept().await {
-            Ok((stream, socket)) => {
-                let tls_acceptor = tls_acceptor.clone();
-                let router_clone = router.clone();
-
-                tokio::spawn(async move {
-                    if let Some(acceptor) = tls_acceptor {
-                        match acceptor.accept(stream).await {
-                            Ok(stream) => {
-                                info!("TLS connection accepted from {}", socket);
-                                handle_connection(stream, socket, &router_clone).await;
-                            }
-                            Err(e) => {
-                                error!("Failed to accept TLS connection from {}: {}", socket, e);
-                            }
-                        }
-                    } else {
-                        handle_connection(stream, socket, &router_clone).await;
-                    }
-                });
-            }
-            Err(e) => {
-                error!("Failed to accept connection: {}", e);
-            }
+        let bytes_read = reader.read(&mut chunk).await?;
+        if bytes_read == 0 {
+            break;
         }
+
+        writer.write_all(format!("{:x}\r\n", bytes_read).as_bytes()).await?;
+        writer.write_all(&chunk[..bytes_read]).await?;
+        writer.write_all(b"\r\n").await?;
     }
+
+    let trailers = trailers.map(|trailers| trailers()).unwrap_or_default();
+    let trailers = trailers
+        .iter()
+        .map(|(k, v)| format!("{}: {}\r\n", k, v))
+        .collect::<String>();
+    writer.write_all(format!("0\r\n{}\r\n", trailers).as_bytes()).await
 }
 
-pub async fn init(router: Router, addrs: &str) {
-    let listener = tokio::net::TcpListener::bind(addrs)
-        .await
-        .expect("Failed to bind address");
+// The four request-target forms of RFC 7230 5.3.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RequestTarget {
+    // "/where?q=now"
+    Origin(String),
+    // "http://www.example.org/pub/WWW/", as sent to proxies
+    Absolute { authority: String, path: String },
+    // "www.example.com:80", only for CONNECT
+    Authority(String),
+    // "*", only for OPTIONS
+    Asterisk,
+}
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, socket)) => {
-                let router_clone = router.clone();
-
-                tokio::spawn(async move {
-                    info!("Connection accepted from {}", socket);
-                    handle_connection(stream, socket, &router_clone).await;
-                });
+impl RequestTarget {
+    pub fn parse(target: &str, method: &str) -> Result<Self, RequestError> {
+        if method == Method::Connect.as_str() {
+            if !target.contains(':') || target.contains(['/', '?', '#', '@']) {
+                return Err("CONNECT requires an authority-form target".into());
             }
-            Err(e) => {
-                error!("Failed to accept connection: {}", e);
+            return Ok(RequestTarget::Authority(target.to_string()));
+        }
+
+        if target == "*" {
+            if method != Method::Options.as_str() {
+                return Err("Asterisk-form target is only allowed with OPTIONS".into());
             }
+            return Ok(RequestTarget::Asterisk);
         }
-    }
-}
 
-async fn handle_connection<T>(mut stream: T, socket: SocketAddr, router: &Router)
-where
-    T: AsyncReadExt + AsyncWriteExt + Unpin,
-{
-    let mut buffer = DynamicBuffer::new(&mut stream);
-    if let Err(e) = &buffer.read_headers_and_body().await {
-        error!("Failed to read from stream: {}", e);
-        return;
+        if target.starts_with('/') {
+            return Ok(RequestTarget::Origin(target.to_string()));
+        }
+
+        match split_absolute_form(target) {
+            Some((authority, path)) => Ok(RequestTarget::Absolute {
+                authority: authority.to_string(),
+                path,
+            }),
+            None => Err("Invalid HTTP request: Invalid request target".into()),
+        }
     }
 
-    let req = HttpRequest::parser(buffer, socket).await;
-    if let Err(e) = &req {
-        error!("Failed to parse request: {}", e);
-        return;
+    // What routing matches on: the path for origin and absolute forms, the target
+    // itself otherwise.
+    pub fn path(&self) -> &str {
+        match self {
+            RequestTarget::Origin(path) | RequestTarget::Absolute { path, .. } => path,
+            RequestTarget::Authority(authority) => authority,
+            RequestTarget::Asterisk => "*",
+        }
     }
+}
 
-    let req = req.unwrap();
+fn split_absolute_form(target: &str) -> Option<(&str, String)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
 
-    let mut writer = Writer {
-        header: Header::new(),
-        body: Vec::new(),
-        status_code: HttpStatus::OK,
-    };
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
 
-    if let Some(handler) = router.get_handler(&req.path) {
-        handler(&mut writer, req);
+    // "http://host" and "http://host?q" address the root resource.
+    if path.starts_with('/') {
+        Some((authority, path.to_string()))
     } else {
-        warn!("No handler found for path: {}", req.path);
-        writer.status_code = HttpStatus::NotFound;
-        writer.body = b"Not Found".to_vec();
+        Some((authority, format!("/{}", path)))
     }
-
-    let response = HttpResponse {
-        headers: writer.header().headers.clone(),
-        status_code: writer.status_code,
-        body: writer.body,
-    };
-
-    send_response(&mut stream, response).await;
 }
 
-async fn send_response<T>(mut stream: T, response: HttpResponse)
-where
-    T: AsyncReadExt + AsyncWriteExt + Unpin,
-{
-    let status_line = format!("HTTP/1.1 {}\r\n", response.status_code.to_string());
-    let headers = response
-        .headers
-        .iter()
-        .map(|(k, v)| format!("{}: {}\r\n", k, v))
-        .collect::<String>();
-    let response = format!(
-        "{}{}\r\n{}",
-        status_line,
-        headers,
-        String::from_utf8_lossy(&response.body)
-    );
+// The generic reason phrase for a code of an unknown status.
+fn class_reason(code: u16) -> &'static str {
+    match code / 100 {
+        1 => "Informational",
+        2 => "Success",
+        3 => "Redirection",
+        4 => "Client Error",
+        _ => "Server Error",
+    }
+}
 
-    if let Err(e) = stream.write(response.as_bytes()).await {
-        error!("Failed to send response: {}", e);
-        return;
+pub fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
     }
 
-    if let Err(e) = stream.flush().await {
-        error!("Failed to flush stream: {}", e);
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum HttpStatus {
     // Informational responses
     Continue,
@@ -351,6 +2743,88 @@ pub enum HttpStatus {
 }
 
 impl HttpStatus {
+    const ALL: [HttpStatus; 62] = [
+        HttpStatus::Continue,
+        HttpStatus::SwitchingProtocols,
+        HttpStatus::Processing,
+        HttpStatus::EarlyHints,
+        HttpStatus::OK,
+        HttpStatus::Created,
+        HttpStatus::Accepted,
+        HttpStatus::NonAuthoritativeInfo,
+        HttpStatus::NoContent,
+        HttpStatus::ResetContent,
+        HttpStatus::PartialContent,
+        HttpStatus::MultiStatus,
+        HttpStatus::AlreadyReported,
+        HttpStatus::IMUsed,
+        HttpStatus::MultipleChoices,
+        HttpStatus::MovedPermanently,
+        HttpStatus::Found,
+        HttpStatus::SeeOther,
+        HttpStatus::NotModified,
+        HttpStatus::UseProxy,
+        HttpStatus::TemporaryRedirect,
+        HttpStatus::PermanentRedirect,
+        HttpStatus::BadRequest,
+        HttpStatus::Unauthorized,
+        HttpStatus::PaymentRequired,
+        HttpStatus::Forbidden,
+        HttpStatus::NotFound,
+        HttpStatus::MethodNotAllowed,
+        HttpStatus::NotAcceptable,
+        HttpStatus::ProxyAuthRequired,
+        HttpStatus::RequestTimeout,
+        HttpStatus::Conflict,
+        HttpStatus::Gone,
+        HttpStatus::LengthRequired,
+        HttpStatus::PreconditionFailed,
+        HttpStatus::RequestEntityTooLarge,
+        HttpStatus::RequestURITooLong,
+        HttpStatus::UnsupportedMediaType,
+        HttpStatus::RequestedRangeNotSatisfiable,
+        HttpStatus::ExpectationFailed,
+        HttpStatus::Teapot,
+        HttpStatus::MisdirectedRequest,
+        HttpStatus::UnprocessableEntity,
+        HttpStatus::Locked,
+        HttpStatus::FailedDependency,
+        HttpStatus::TooEarly,
+        HttpStatus::UpgradeRequired,
+        HttpStatus::PreconditionRequired,
+        HttpStatus::TooManyRequests,
+        HttpStatus::RequestHeaderFieldsTooLarge,
+        HttpStatus::UnavailableForLegalReasons,
+        HttpStatus::InternalServerError,
+        HttpStatus::NotImplemented,
+        HttpStatus::BadGateway,
+        HttpStatus::ServiceUnavailable,
+        HttpStatus::GatewayTimeout,
+        HttpStatus::HTTPVersionNotSupported,
+        HttpStatus::VariantAlsoNegotiates,
+        HttpStatus::InsufficientStorage,
+        HttpStatus::LoopDetected,
+        HttpStatus::NotExtended,
+        HttpStatus::NetworkAuthenticationRequired,
+    ];
+
+    pub fn from_code(code: u16) -> Option<HttpStatus> {
+        HttpStatus::ALL.iter().find(|status| status.code() == code).cloned()
+    }
+
+    pub fn code(&self) -> u16 {
+        self.to_string()[..3].parse().unwrap_or(500)
+    }
+
+    // The reason phrase alone, e.g. "Not Found".
+    pub fn reason(&self) -> &'static str {
+        &self.to_string()[4..]
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
     pub fn to_string(&self) -> &'static str {
         match self {
             // Informational responses
@@ -426,4 +2900,502 @@ impl HttpStatus {
             HttpStatus::NetworkAuthenticationRequired => "511 Network Authentication Required",
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socket() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    // A small xorshift generator, so every run mutates the same way.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn post_with_zero_content_length_has_empty_body() {
+        let raw = b"POST /submit HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n";
+        let req = HttpRequest::from_bytes(raw, socket()).unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.content_length, Some(0));
+        assert!(req.body.is_empty());
+        assert_eq!(req.body_size, 0);
+    }
+
+    #[tokio::test]
+    async fn zero_content_length_leaves_the_next_request_buffered() {
+        let raw = b"POST /a HTTP/1.1\r\nContent-Length: 0\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let config = Config::new();
+        let mut buffer = DynamicBuffer::new(tokio::io::join(&raw[..], tokio::io::sink()));
+        assert!(buffer.read_headers(&config).await.unwrap());
+        let mut req = HttpRequest::parse_head(&buffer.headers, socket(), &config).unwrap();
+        req.receive_body(&mut buffer, &config, None).await.unwrap();
+
+        assert!(req.body.is_empty());
+        assert!(buffer.headers.starts_with(b"GET /b HTTP/1.1"));
+    }
+
+    type Log = Arc<std::sync::Mutex<Vec<String>>>;
+
+    // Records entering and leaving, so the log shows how the layers nest.
+    fn logging(name: &'static str, log: &Log) -> Middleware {
+        let log = log.clone();
+        Arc::new(move |handler: Handler| {
+            let log = log.clone();
+            Arc::new(move |w: &mut Writer, r: HttpRequest| {
+                log.lock().unwrap().push(format!("{} in", name));
+                handler(w, r);
+                log.lock().unwrap().push(format!("{} out", name));
+            })
+        })
+    }
+
+    async fn send(router: &Router, raw: &[u8]) -> Writer {
+        let mut writer = router.new_writer();
+        let req = HttpRequest::from_bytes(raw, socket()).unwrap();
+        dispatch(router, &mut writer, req).await;
+        writer
+    }
+
+    #[tokio::test]
+    async fn middlewares_run_in_registration_order() {
+        let log = Log::default();
+        let mut router = Router::new();
+        let handler_log = log.clone();
+        router.handle_func(
+            "/",
+            Arc::new(move |_w: &mut Writer, _r: HttpRequest| {
+                handler_log.lock().unwrap().push("handler".to_string());
+            }),
+        );
+        router.use_middleware(logging("a", &log));
+        router.use_middleware(logging("b", &log));
+        router.insert_middleware(0, logging("first", &log));
+        router.insert_middleware(2, logging("between", &log));
+        router.use_route_middleware("/", logging("route", &log));
+
+        send(&router, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await;
+
+        let expected = [
+            "first in", "a in", "between in", "b in", "route in", "handler", "route out",
+            "b out", "between out", "a out", "first out",
+        ];
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn router_respects_a_stopping_guard() {
+        use super::super::middleware::{guard, Flow};
+
+        let log = Log::default();
+        let mut router = Router::new();
+        let handler_log = log.clone();
+        router.handle_func(
+            "/",
+            Arc::new(move |_w: &mut Writer, _r: HttpRequest| {
+                handler_log.lock().unwrap().push("handler".to_string());
+            }),
+        );
+        router.use_middleware(guard(|w: &mut Writer, _r: &HttpRequest| {
+            w.write_header(HttpStatus::TooManyRequests);
+            Flow::Stop
+        }));
+        router.use_middleware(logging("inner", &log));
+
+        let writer = send(&router, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await;
+
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(*writer.status(), HttpStatus::TooManyRequests);
+    }
+
+    #[test]
+    fn text_sets_a_plain_utf8_content_type() {
+        let mut w = Writer::new();
+        w.header().set(header::CONTENT_TYPE, "application/json");
+        w.text("héllo");
+
+        assert_eq!(w.header().get(header::CONTENT_TYPE), Some("text/plain; charset=utf-8"));
+        assert_eq!(w.body, "héllo".as_bytes());
+        assert_eq!(*w.status(), HttpStatus::OK);
+    }
+
+    #[test]
+    fn origin_form_target() {
+        let target = RequestTarget::parse("/where?q=now", "GET").unwrap();
+        assert_eq!(target, RequestTarget::Origin("/where?q=now".to_string()));
+        assert_eq!(target.path(), "/where?q=now");
+
+        let raw = b"GET /where?q=now HTTP/1.1\r\nHost: a\r\n\r\n";
+        let req = HttpRequest::from_bytes(raw, socket()).unwrap();
+        assert_eq!(req.path, "/where");
+        assert_eq!(req.query(), Some("q=now"));
+    }
+
+    #[test]
+    fn absolute_form_target() {
+        let target = RequestTarget::parse("http://user@www.example.org:8080/pub?x=1", "GET");
+        let target = target.unwrap();
+        assert_eq!(
+            target,
+            RequestTarget::Absolute {
+                authority: "www.example.org:8080".to_string(),
+                path: "/pub?x=1".to_string(),
+            }
+        );
+        assert_eq!(RequestTarget::parse("https://example.org", "GET").unwrap().path(), "/");
+        assert_eq!(RequestTarget::parse("http://example.org?q", "GET").unwrap().path(), "/?q");
+        assert!(RequestTarget::parse("ftp://example.org/", "GET").is_err());
+
+        // The authority in the target wins over Host.
+        let raw = b"GET http://www.example.org/pub HTTP/1.1\r\nHost: other\r\n\r\n";
+        let req = HttpRequest::from_bytes(raw, socket()).unwrap();
+        assert_eq!(req.path, "/pub");
+        assert_eq!(req.host.as_deref(), Some("www.example.org"));
+    }
+
+    #[test]
+    fn authority_form_target() {
+        let target = RequestTarget::parse("www.example.com:443", "CONNECT").unwrap();
+        assert_eq!(target, RequestTarget::Authority("www.example.com:443".to_string()));
+        assert_eq!(target.path(), "www.example.com:443");
+
+        assert!(RequestTarget::parse("www.example.com", "CONNECT").is_err());
+        assert!(RequestTarget::parse("www.example.com:443/x", "CONNECT").is_err());
+        assert!(RequestTarget::parse("/", "CONNECT").is_err());
+        assert!(RequestTarget::parse("www.example.com:443", "GET").is_err());
+    }
+
+    #[test]
+    fn asterisk_form_target() {
+        let target = RequestTarget::parse("*", "OPTIONS").unwrap();
+        assert_eq!(target, RequestTarget::Asterisk);
+        assert_eq!(target.path(), "*");
+        assert!(RequestTarget::parse("*", "GET").is_err());
+
+        let raw = b"OPTIONS * HTTP/1.1\r\nHost: a\r\n\r\n";
+        let req = HttpRequest::from_bytes(raw, socket()).unwrap();
+        assert_eq!(req.path, "*");
+    }
+
+    #[test]
+    fn parser_rejects_obfuscated_headers() {
+        for field in [
+            &b"Host : a"[..],
+            b" X-Folded: a",
+            b"X-Name\0: a",
+            b"X-Value: a\rb",
+            b"X-Value: a\0b",
+            b"No-Colon",
+        ] {
+            let mut raw = b"GET / HTTP/1.1\r\nHost: a\r\n".to_vec();
+            raw.extend_from_slice(field);
+            raw.extend_from_slice(b"\r\n\r\n");
+            assert!(HttpRequest::from_bytes(&raw, socket()).is_err(), "accepted {:?}", field);
+        }
+    }
+
+    // Mutates valid requests at random and checks that parsing never panics and that
+    // whatever it accepts holds only well-formed fields.
+    #[test]
+    fn fuzz_parser() {
+        let seeds: [&[u8]; 3] = [
+            b"GET /a?b=c HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n",
+            b"POST /up HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello",
+            b"POST /up HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n",
+        ];
+        let alphabet = b"\r\n\0\t :;,/?#%-09AZaz\x7f\xff";
+
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let mut raw = seeds[rng.below(seeds.len())].to_vec();
+            for _ in 0..1 + rng.below(4) {
+                let at = rng.below(raw.len());
+                let byte = alphabet[rng.below(alphabet.len())];
+                match rng.below(3) {
+                    0 => raw[at] = byte,
+                    1 => raw.insert(at, byte),
+                    _ => {
+                        raw.remove(at);
+                    }
+                }
+            }
+
+            let Ok(req) = HttpRequest::from_bytes(&raw, socket()) else {
+                continue;
+            };
+            for (name, value) in &req.headers {
+                assert!(header::is_token(name), "name {:?} from {:?}", name, raw);
+                assert!(
+                    !value.contains(['\r', '\n', '\0']),
+                    "value {:?} from {:?}",
+                    value,
+                    raw
+                );
+            }
+            assert_eq!(req.body.len(), req.body_size);
+        }
+    }
+
+    // Reads one response with a Content-Length body, returning its head and body.
+    async fn read_response(stream: &mut tokio::net::TcpStream) -> (String, Vec<u8>) {
+        let mut raw = Vec::new();
+        let mut byte = [0; 1];
+        while !raw.ends_with(b"\r\n\r\n") {
+            assert_eq!(stream.read(&mut byte).await.unwrap(), 1, "closed mid-response");
+            raw.push(byte[0]);
+        }
+        let head = String::from_utf8(raw).unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (head, body)
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_keep_alive_connections() {
+        let mut router = Router::new();
+        router.config().keep_alive_timeout = Duration::from_secs(60);
+        router.handle_func(
+            "/",
+            Arc::new(|w: &mut Writer, _: HttpRequest| w.write(b"done")),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, router, None, async {
+            let _ = stopped.await;
+        }));
+
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut busy = tokio::net::TcpStream::connect(addr).await.unwrap();
+        for stream in [&mut idle, &mut busy] {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+            let (head, _) = read_response(stream).await;
+            assert!(head.contains("Connection: keep-alive"), "{}", head);
+        }
+
+        // Half a request is in when shutdown starts; it is still served.
+        busy.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The listener is gone while that request is still draining.
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        // The idle connection is closed rather than kept for keep_alive_timeout.
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(1), idle.read_to_end(&mut rest));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+
+        busy.write_all(b"Host: a\r\n\r\n").await.unwrap();
+        let (head, body) = read_response(&mut busy).await;
+        assert!(head.contains("Connection: close"), "{}", head);
+        assert_eq!(body, b"done");
+        assert_eq!(busy.read(&mut [0; 1]).await.unwrap(), 0);
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_admits_by_the_client_address() {
+        let peers: Arc<std::sync::Mutex<Vec<SocketAddr>>> = Arc::default();
+        let mut router = Router::new();
+        router.config().proxy_protocol = true;
+        router.config().max_connections_per_ip = Some(1);
+        let seen = peers.clone();
+        router.config().on_accept = Some(Arc::new(move |peer: SocketAddr| {
+            seen.lock().unwrap().push(peer);
+            Ok(())
+        }));
+        router.handle_func(
+            "/",
+            Arc::new(|w: &mut Writer, r: HttpRequest| {
+                w.write(r.socket.ip().to_string().as_bytes())
+            }),
+        );
+        let stats = router.stats();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, router, None, pending()));
+
+        let mut clients = Vec::new();
+        for source in ["192.0.2.1 198.51.100.1 5000", "192.0.2.2 198.51.100.1 5001"] {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let proxied = format!("PROXY TCP4 {} 443\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n", source);
+            client.write_all(proxied.as_bytes()).await.unwrap();
+            let (_, body) = read_response(&mut client).await;
+            assert_eq!(body, source.split(' ').next().unwrap().as_bytes());
+            clients.push(client);
+        }
+
+        // Both came from 127.0.0.1, but only a second 192.0.2.1 is over the limit.
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let proxied = "PROXY TCP4 192.0.2.1 198.51.100.1 5002 443\r\nGET / HTTP/1.1\r\n\r\n";
+        client.write_all(proxied.as_bytes()).await.unwrap();
+        // Closed unanswered; with the request unread that may come as a reset.
+        assert!(matches!(client.read(&mut [0; 1]).await, Ok(0) | Err(_)));
+
+        let peers = peers.lock().unwrap();
+        let ips: Vec<_> = peers.iter().map(|peer| peer.ip().to_string()).collect();
+        assert_eq!(ips, ["192.0.2.1", "192.0.2.2"]);
+        assert_eq!(stats.active_from("192.0.2.1".parse().unwrap()), 1);
+        assert_eq!(stats.active_from("127.0.0.1".parse().unwrap()), 0);
+        assert_eq!(stats.snapshot().rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn copy_body_to_sends_the_body_once() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let mut req = HttpRequest::from_bytes(raw, socket()).unwrap();
+        let mut sink = Vec::new();
+        assert_eq!(req.copy_body_to(&mut sink).await.unwrap(), 5);
+        assert_eq!(req.copy_body_to(&mut sink).await.unwrap(), 0);
+        assert_eq!(sink, b"hello");
+    }
+
+    #[tokio::test]
+    async fn informational_final_status_drops_the_handler_entity_headers() {
+        let mut response = HttpResponse::new(HttpStatus::Continue);
+        for (key, value) in [
+            ("Content-Type", "application/json"),
+            ("Content-Encoding", "gzip"),
+            ("ETag", "\"abc\""),
+            ("X-Request-Id", "1"),
+        ] {
+            response.headers.insert(key.to_string(), value.to_string());
+        }
+        response.body = b"{}".to_vec();
+
+        let mut out = Vec::new();
+        assert!(write_response(tokio::io::join(&b""[..], &mut out), response, true, false).await);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", out);
+        assert!(out.contains("X-Request-Id: 1\r\n"), "{}", out);
+        assert!(out.contains("Content-Length: 0\r\n"), "{}", out);
+        for name in ["Content-Type", "Content-Encoding", "ETag"] {
+            assert!(!out.contains(name), "{}", out);
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_and_panicked_handlers_keep_the_request_headers() {
+        let mut router = Router::new();
+        router
+            .config()
+            .default_headers
+            .insert("X-Served-By".to_string(), "routs".to_string());
+        router.handle_func(
+            "/slow",
+            Arc::new(|w: &mut Writer, _: HttpRequest| {
+                std::thread::sleep(Duration::from_millis(200));
+                w.write(b"late");
+            }),
+        );
+        router.set_timeout("/slow", Duration::from_millis(20));
+        router.handle_func(
+            "/panic",
+            Arc::new(|_: &mut Writer, _: HttpRequest| panic!("handler failed")),
+        );
+        router.set_timeout("/panic", Duration::from_secs(5));
+
+        for (path, status) in [
+            ("/slow", HttpStatus::GatewayTimeout),
+            ("/panic", HttpStatus::InternalServerError),
+        ] {
+            let mut writer = router.new_writer();
+            writer.header().set(header::X_REQUEST_ID, "abc-1");
+            let raw = format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path);
+            let req = HttpRequest::from_bytes(raw.as_bytes(), socket()).unwrap();
+            dispatch(&router, &mut writer, req).await;
+
+            assert_eq!(*writer.status(), status, "{}", path);
+            assert_eq!(writer.header().get(header::X_REQUEST_ID), Some("abc-1"));
+            assert_eq!(writer.header().get("X-Served-By"), Some("routs"));
+        }
+    }
+
+    #[tokio::test]
+    async fn only_inflated_bodies_lose_their_content_encoding() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write as _;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[b'a'; 64]).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let mut raw = format!(
+            "POST /up HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            gzip.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(&gzip);
+
+        let mut config = Config::new();
+        config.decompress_requests = true;
+        for threshold in [None, Some(8)] {
+            config.upload_threshold = threshold;
+            config.upload_dir = std::env::temp_dir();
+            let mut buffer = DynamicBuffer::new(tokio::io::join(&raw[..], tokio::io::sink()));
+            assert!(buffer.read_headers(&config).await.unwrap());
+            let mut req = HttpRequest::parse_head(&buffer.headers, socket(), &config).unwrap();
+            req.receive_body(&mut buffer, &config, None).await.unwrap();
+
+            match &req.upload {
+                None => {
+                    assert_eq!(req.body, [b'a'; 64]);
+                    assert_eq!(req.header(header::CONTENT_ENCODING), None);
+                }
+                Some(file) => {
+                    assert_eq!(std::fs::read(file.path()).unwrap(), gzip);
+                    assert_eq!(req.header(header::CONTENT_ENCODING), Some("gzip"));
+                }
+            }
+        }
+
+        // A streamed body reaches the handler as sent, header and all.
+        type Seen = Option<(Option<String>, Vec<u8>)>;
+        let seen: Arc<std::sync::Mutex<Seen>> = Arc::default();
+        let mut router = Router::new();
+        router.config().decompress_requests = true;
+        router.stream_body("/up");
+        let handler_seen = seen.clone();
+        router.handle_func(
+            "/up",
+            Arc::new(move |_: &mut Writer, mut r: HttpRequest| {
+                let encoding = r.header(header::CONTENT_ENCODING).map(str::to_string);
+                let mut body = Vec::new();
+                let copy = r.copy_body_to(&mut body);
+                tokio::runtime::Handle::current().block_on(copy).unwrap();
+                *handler_seen.lock().unwrap() = Some((encoding, body));
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, router, None, pending()));
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(&raw).await.unwrap();
+        read_response(&mut client).await;
+
+        let seen = seen.lock().unwrap().take().unwrap();
+        assert_eq!(seen, (Some("gzip".to_string()), gzip));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{sync::Arc, time::Instant};
+
+use log::info;
+use serde_json::json;
+
+use super::{
+    header,
+    http::{Handler, HttpRequest, HttpStatus, Writer},
+};
+
+// Wraps a handler in another one. Middlewares added to a Router form an onion: the
+// first one registered is the outermost and sees the request first.
+pub type Middleware = Arc<dyn Fn(Handler) -> Handler + Send + Sync>;
+
+// What a `guard` decided about a request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Flow {
+    // Hand the request on to the next layer.
+    Next,
+    // The guard wrote the response itself; nothing inside it runs.
+    Stop,
+}
+
+// A middleware that checks each request before the rest of the chain, e.g. for
+// auth or rate limiting. On `Flow::Stop` the response is whatever the guard wrote
+// to the Writer, and neither the inner middlewares nor the handler are called.
+pub fn guard<F>(check: F) -> Middleware
+where
+    F: Fn(&mut Writer, &HttpRequest) -> Flow + Send + Sync + 'static,
+{
+    let check = Arc::new(check);
+    Arc::new(move |handler: Handler| {
+        let check = check.clone();
+        Arc::new(move |w: &mut Writer, r: HttpRequest| {
+            if check(w, &r) == Flow::Next {
+                handler(w, r);
+            }
+        })
+    })
+}
+
+pub fn bearer_auth<F>(validate: F, handler: Handler) -> Handler
+where
+    F: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    Arc::new(move |w: &mut Writer, r: HttpRequest| match r.bearer_token() {
+        Some(token) if validate(&token) => handler(w, r),
+        _ => {
+            w.header().set(header::WWW_AUTHENTICATE, "Bearer");
+            w.write_header(HttpStatus::Unauthorized);
+        }
+    })
+}
+
+pub fn https_redirect(https_port: u16) -> Handler {
+    Arc::new(move |w: &mut Writer, r: HttpRequest| redirect_to_https(w, &r, https_port))
+}
+
+pub fn redirect_to_https(w: &mut Writer, r: &HttpRequest, https_port: u16) {
+    let host = match &r.host {
+        Some(host) => host,
+        None => {
+            w.write_header(HttpStatus::BadRequest);
+            w.write(b"Missing Host header");
+            return;
+        }
+    };
+
+    let port = if https_port == 443 {
+        String::new()
+    } else {
+        format!(":{}", https_port)
+    };
+    let location = format!("https://{}{}{}", host, port, r.path);
+
+    // 308 keeps the method and body, but HTTP/1.0 clients only know 301.
+    let status = if r.version == "HTTP/1.0" {
+        HttpStatus::MovedPermanently
+    } else {
+        HttpStatus::PermanentRedirect
+    };
+
+    w.redirect_with_query(r, &location, status);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub fn access_log(handler: Handler) -> Handler {
+    access_log_with_format(LogFormat::Text, handler)
+}
+
+pub fn access_log_with_format(format: LogFormat, handler: Handler) -> Handler {
+    Arc::new(move |w: &mut Writer, r: HttpRequest| {
+        let started = Instant::now();
+        let method = r.method.clone();
+        let path = r.path.clone();
+        let pattern = r.matched_pattern.clone();
+        let socket = r.socket;
+        let body_size = r.body_size;
+        let content_length = r.content_length;
+        let request_id = r.header(header::X_REQUEST_ID).map(|id| id.to_string());
+
+        handler(w, r);
+
+        let elapsed = started.elapsed();
+        match format {
+            LogFormat::Text => info!(
+                "{} \"{} {}\" {} body={} declared={} {:?}",
+                socket,
+                method,
+                path,
+                w.status().code(),
+                body_size,
+                content_length.map_or("-".to_string(), |length| length.to_string()),
+                elapsed
+            ),
+            LogFormat::Json => info!(
+                "{}",
+                json!({
+                    "method": method,
+                    "path": path,
+                    "route": pattern,
+                    "status": w.status().code(),
+                    "duration_ms": elapsed.as_secs_f64() * 1000.0,
+                    "remote_ip": socket.ip().to_string(),
+                    "request_id": request_id,
+                    "body_size": body_size,
+                    "content_length": content_length,
+                })
+            ),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn request(raw: &[u8]) -> HttpRequest {
+        HttpRequest::from_bytes(raw, "127.0.0.1:40000".parse().unwrap()).unwrap()
+    }
+
+    fn counting_handler(calls: &Arc<AtomicUsize>) -> Handler {
+        let calls = calls.clone();
+        Arc::new(move |w: &mut Writer, _r: HttpRequest| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            w.text("handled");
+        })
+    }
+
+    fn require_token() -> Middleware {
+        guard(|w: &mut Writer, r: &HttpRequest| {
+            if r.header(header::AUTHORIZATION).is_some() {
+                return Flow::Next;
+            }
+            w.header().set(header::WWW_AUTHENTICATE, "Bearer");
+            w.write_header(HttpStatus::Unauthorized);
+            w.write(b"denied");
+            Flow::Stop
+        })
+    }
+
+    #[test]
+    fn stopping_guard_owns_the_response() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = require_token()(counting_handler(&calls));
+
+        let mut w = Writer::new();
+        handler(&mut w, request(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(*w.status(), HttpStatus::Unauthorized);
+        assert_eq!(w.header().get(header::WWW_AUTHENTICATE), Some("Bearer"));
+        assert_eq!(w.buffered_body(), Some(&b"denied"[..]));
+    }
+
+    #[test]
+    fn stopping_guard_skips_inner_middlewares() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner_calls = calls.clone();
+        let inner: Middleware = Arc::new(move |handler: Handler| {
+            let inner_calls = inner_calls.clone();
+            Arc::new(move |w: &mut Writer, r: HttpRequest| {
+                inner_calls.fetch_add(1, Ordering::SeqCst);
+                handler(w, r);
+            })
+        });
+        let handler = require_token()(inner(counting_handler(&calls)));
+
+        let mut w = Writer::new();
+        handler(&mut w, request(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(*w.status(), HttpStatus::Unauthorized);
+    }
+
+    #[test]
+    fn passing_guard_calls_the_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = require_token()(counting_handler(&calls));
+
+        let mut w = Writer::new();
+        handler(&mut w, request(b"GET / HTTP/1.1\r\nHost: a\r\nAuthorization: Bearer t\r\n\r\n"));
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(*w.status(), HttpStatus::OK);
+        assert_eq!(w.buffered_body(), Some(&b"handled"[..]));
+    }
+}
@@ -1,3 +1,20 @@
 pub mod http;
 pub mod buffer;
-pub mod ssl_tls;
\ No newline at end of file
+#[cfg(feature = "tls")]
+pub mod ssl_tls;
+pub mod config;
+pub mod upload;
+pub mod middleware;
+pub mod error;
+pub mod method;
+pub mod connection;
+pub mod header;
+pub mod request_id;
+pub mod files;
+pub mod upgrade;
+pub mod route;
+pub mod form;
+pub mod cache;
+pub mod decompress;
+pub mod proxy;
+pub mod encoding;
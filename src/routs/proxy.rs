@@ -0,0 +1,115 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// A v1 line is at most 107 bytes, CRLF included.
+const V1_MAX_LENGTH: usize = 107;
+
+// Reads the PROXY protocol header (v1 or v2) a load balancer sends before anything
+// else, and returns the client address it carries. `peer`, the balancer itself,
+// is returned for health checks (v2 LOCAL) and for clients it can't describe (v1
+// UNKNOWN, non-IP v2 families). Exactly the header is consumed, so the stream is
+// left at the first byte of the client's data.
+pub async fn read_header<T>(stream: &mut T, peer: SocketAddr) -> io::Result<SocketAddr>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+    match &start {
+        b"PROXY" => read_v1(stream, peer).await,
+        _ if start == V2_SIGNATURE[..5] => read_v2(stream, peer).await,
+        _ => Err(invalid("Missing PROXY protocol header")),
+    }
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+async fn read_v1<T>(stream: &mut T, peer: SocketAddr) -> io::Result<SocketAddr>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header is not ASCII"))?;
+    let mut fields = line.split(' ').skip(1);
+    let family = fields.next().unwrap_or_default();
+    if family == "UNKNOWN" {
+        return Ok(peer);
+    }
+
+    let fields: Vec<&str> = fields.collect();
+    let [source, _, port, _] = fields[..] else {
+        return Err(invalid("Malformed PROXY header"));
+    };
+    let ip: IpAddr = match family {
+        "TCP4" => source.parse::<Ipv4Addr>().map(IpAddr::V4),
+        "TCP6" => source.parse::<Ipv6Addr>().map(IpAddr::V6),
+        _ => return Err(invalid("Unsupported PROXY protocol family")),
+    }
+    .map_err(|_| invalid("Invalid PROXY source address"))?;
+    let port = port
+        .parse()
+        .map_err(|_| invalid("Invalid PROXY source port"))?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn read_v2<T>(stream: &mut T, peer: SocketAddr) -> io::Result<SocketAddr>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut head = [0; 11];
+    stream.read_exact(&mut head).await?;
+    if head[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("Invalid PROXY v2 signature"));
+    }
+
+    let (version_command, family) = (head[7], head[8]);
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    let mut addresses = vec![0; u16::from_be_bytes([head[9], head[10]]) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection, e.g. a health check.
+        0 => return Ok(peer),
+        1 => {}
+        _ => return Err(invalid("Unsupported PROXY v2 command")),
+    }
+
+    // Source address, destination address, source port, destination port; any TLVs
+    // after them are ignored.
+    let source = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(IpAddr::from(ip), port)
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(IpAddr::from(ip), port)
+        }
+        1 | 2 => return Err(invalid("Truncated PROXY v2 addresses")),
+        _ => peer,
+    };
+
+    Ok(source)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{Log, Metadata, Record};
+
+use super::header;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+const MAX_LENGTH: usize = 128;
+
+// The ID of the request being handled on this task, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+pub async fn scope<F>(id: String, f: F) -> F::Output
+where
+    F: Future,
+{
+    REQUEST_ID.scope(id, f).await
+}
+
+// Same as `scope` for code that runs outside the task, e.g. on a blocking thread.
+pub fn sync_scope<F, R>(id: String, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    REQUEST_ID.sync_scope(id, f)
+}
+
+// Keeps the client's X-Request-Id when it looks sane and generates one otherwise.
+// The chosen ID is written back into the headers so handlers see it.
+pub fn from_headers(headers: &mut HashMap<String, String>) -> String {
+    let id = header::get(headers, header::X_REQUEST_ID)
+        .filter(|id| is_valid(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(generate);
+
+    headers.retain(|k, _| !k.eq_ignore_ascii_case(header::X_REQUEST_ID));
+    headers.insert(header::X_REQUEST_ID.to_string(), id.clone());
+    id
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+pub(crate) fn generate() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:016x}-{:x}", nanos, count)
+}
+
+// Wraps a logger so records emitted while a request is handled start with its ID.
+pub struct RequestIdLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> RequestIdLogger<L> {
+    pub fn new(inner: L) -> Self {
+        RequestIdLogger { inner }
+    }
+}
+
+impl<L: Log> Log for RequestIdLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match current() {
+            Some(id) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Installs env_logger (configured from RUST_LOG) behind a RequestIdLogger.
+pub fn init_logger() {
+    let logger = env_logger::Builder::from_default_env().build();
+    let max_level = logger.filter();
+
+    if log::set_boxed_logger(Box::new(RequestIdLogger::new(logger))).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
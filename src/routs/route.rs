@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use super::{
+    http::{IntoHandler, Router},
+    method::Method,
+    middleware::Middleware,
+};
+
+// Configures one path in a chain, e.g.
+// `router.route("/upload").post(handler).max_body(50 << 20).timeout(secs(30))`.
+// Settings apply to the path as a whole, whatever order they are given in.
+pub struct RouteBuilder<'a> {
+    router: &'a mut Router,
+    path: String,
+}
+
+impl<'a> RouteBuilder<'a> {
+    pub(crate) fn new(router: &'a mut Router, path: &str) -> Self {
+        RouteBuilder {
+            router,
+            path: path.to_string(),
+        }
+    }
+
+    // Handles every method without a handler of its own on this path.
+    pub fn any(self, handler: impl IntoHandler) -> Self {
+        self.router.handle_func(&self.path, handler);
+        self
+    }
+
+    pub fn method(self, method: Method, handler: impl IntoHandler) -> Self {
+        self.router.handle_method(method, &self.path, handler);
+        self
+    }
+
+    pub fn get(self, handler: impl IntoHandler) -> Self {
+        self.method(Method::Get, handler)
+    }
+
+    pub fn post(self, handler: impl IntoHandler) -> Self {
+        self.method(Method::Post, handler)
+    }
+
+    pub fn put(self, handler: impl IntoHandler) -> Self {
+        self.method(Method::Put, handler)
+    }
+
+    pub fn patch(self, handler: impl IntoHandler) -> Self {
+        self.method(Method::Patch, handler)
+    }
+
+    pub fn delete(self, handler: impl IntoHandler) -> Self {
+        self.method(Method::Delete, handler)
+    }
+
+    pub fn middleware(self, middleware: Middleware) -> Self {
+        self.router.use_route_middleware(&self.path, middleware);
+        self
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.router.set_timeout(&self.path, timeout);
+        self
+    }
+
+    pub fn max_body(self, limit: usize) -> Self {
+        self.router.set_body_limit(&self.path, limit);
+        self
+    }
+
+    pub fn stream_body(self) -> Self {
+        self.router.stream_body(&self.path);
+        self
+    }
+}
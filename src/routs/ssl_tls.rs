@@ -1,6 +1,11 @@
-use std::{fs::File, io::BufReader, sync::Arc};
+use std::{collections::HashMap, fs::File, io::BufReader, sync::Arc};
 
-use rustls::ServerConfig;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey},
+    ConfigBuilder, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion, WantsVerifier,
+    DEFAULT_CIPHER_SUITES,
+};
 use rustls_pemfile::certs;
 
 pub fn load_certs(path: &str) -> Vec<rustls::Certificate> {
@@ -17,15 +22,128 @@ pub fn load_private_key(path: &str) -> rustls::PrivateKey {
     rustls::PrivateKey(keys[0].clone())
 }
 
-pub fn configure_tls(cert_path: &str, key_path: &str) -> Arc<ServerConfig> {
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn protocol(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+// Protocol range and cipher suites offered during the handshake. The defaults
+// match rustls' safe defaults.
+#[derive(Clone)]
+pub struct TlsOptions {
+    pub min_version: TlsVersion,
+    pub max_version: TlsVersion,
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+}
+
+impl TlsOptions {
+    pub fn new() -> Self {
+        TlsOptions {
+            min_version: TlsVersion::Tls12,
+            max_version: TlsVersion::Tls13,
+            cipher_suites: DEFAULT_CIPHER_SUITES.to_vec(),
+        }
+    }
+
+    fn builder(&self) -> ConfigBuilder<ServerConfig, WantsVerifier> {
+        let versions: Vec<&'static SupportedProtocolVersion> =
+            [TlsVersion::Tls12, TlsVersion::Tls13]
+                .iter()
+                .filter(|v| **v >= self.min_version && **v <= self.max_version)
+                .map(TlsVersion::protocol)
+                .collect();
+
+        ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&versions)
+            .expect("Invalid TLS version or cipher suite configuration")
+    }
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        TlsOptions::new()
+    }
+}
+
+pub fn configure_tls(cert_path: &str, key_path: &str, options: &TlsOptions) -> Arc<ServerConfig> {
     let certs = load_certs(cert_path);
     let key = load_private_key(key_path);
 
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
+    let config = options
+        .builder()
         .with_no_client_auth()
         .with_single_cert(certs, key)
         .expect("Failed to configure TLS");
 
     Arc::new(config)
 }
+
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> Arc<CertifiedKey> {
+    let certs = load_certs(cert_path);
+    let key = load_private_key(key_path);
+    let signing_key = any_supported_type(&key).expect("Unsupported private key type");
+
+    Arc::new(CertifiedKey::new(certs, signing_key))
+}
+
+pub struct SniResolver {
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    pub fn new() -> Self {
+        SniResolver {
+            certs: HashMap::new(),
+            default: None,
+        }
+    }
+
+    pub fn add(&mut self, hostname: &str, cert_path: &str, key_path: &str) {
+        self.certs.insert(
+            hostname.to_ascii_lowercase(),
+            load_certified_key(cert_path, key_path),
+        );
+    }
+
+    pub fn set_default(&mut self, cert_path: &str, key_path: &str) {
+        self.default = Some(load_certified_key(cert_path, key_path));
+    }
+}
+
+impl Default for SniResolver {
+    fn default() -> Self {
+        SniResolver::new()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.certs.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+pub fn configure_sni_tls(resolver: SniResolver, options: &TlsOptions) -> Arc<ServerConfig> {
+    let config = options
+        .builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+
+    Arc::new(config)
+}
@@ -0,0 +1,69 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+pub(crate) type Hijack =
+    Box<dyn FnOnce(Upgraded) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+// The raw connection handed to a handler that hijacked it, once the response has
+// been sent. Bytes the client sent after the request are read first.
+pub struct Upgraded {
+    io: Box<dyn Io>,
+    buffered: Vec<u8>,
+    offset: usize,
+}
+
+impl Upgraded {
+    pub(crate) fn new(io: Box<dyn Io>, buffered: Vec<u8>) -> Self {
+        Upgraded {
+            io,
+            buffered,
+            offset: 0,
+        }
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.offset < self.buffered.len() {
+            let len = buf.remaining().min(self.buffered.len() - self.offset);
+            let start = self.offset;
+            buf.put_slice(&self.buffered[start..start + len]);
+            self.offset += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use super::{buffer::read_within, error::RequestError};
+
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct UploadedFile {
+    path: PathBuf,
+    size: usize,
+    keep: bool,
+}
+
+impl UploadedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub async fn open(&self) -> std::io::Result<File> {
+        File::open(&self.path).await
+    }
+
+    // Moves the file out of the temp directory; it is no longer removed on drop.
+    pub fn persist(mut self, dest: impl AsRef<Path>) -> std::io::Result<PathBuf> {
+        let dest = dest.as_ref().to_path_buf();
+        if std::fs::rename(&self.path, &dest).is_err() {
+            std::fs::copy(&self.path, &dest)?;
+            let _ = std::fs::remove_file(&self.path);
+        }
+        self.keep = true;
+        Ok(dest)
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove upload file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn temp_path(dir: &Path) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let count = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(
+        "routs-upload-{}-{}-{}",
+        process::id(),
+        nanos,
+        count
+    ))
+}
+
+// Creates a new upload file only the server's user can read. `create_new` never
+// follows a symlink or reuses a file someone else put at the guessed name; a taken
+// name is just retried with the next one.
+async fn create_temp(dir: &Path) -> std::io::Result<(PathBuf, File)> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut attempts = 0;
+    loop {
+        let path = temp_path(dir);
+        match options.open(&path).await {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && attempts < 16 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub async fn stream_to_disk<T>(
+    stream: &mut T,
+    prefix: &[u8],
+    content_length: usize,
+    dir: &Path,
+    read_timeout: Option<Duration>,
+) -> Result<UploadedFile, RequestError>
+where
+    T: AsyncReadExt + Unpin,
+{
+    let (path, mut file) = create_temp(dir).await.map_err(|e| e.to_string())?;
+
+    // From here on the guard removes the partial file if anything fails.
+    let mut upload = UploadedFile {
+        path,
+        size: 0,
+        keep: false,
+    };
+
+    let prefix = &prefix[..prefix.len().min(content_length)];
+    file.write_all(prefix).await.map_err(|e| e.to_string())?;
+    upload.size = prefix.len();
+
+    let mut chunk = vec![0; 8192];
+    while upload.size < content_length {
+        let wanted = chunk.len().min(content_length - upload.size);
+        let bytes_read = read_within(stream, &mut chunk[..wanted], read_timeout).await?;
+        if bytes_read == 0 {
+            return Err("Connection closed before reading full body".into());
+        }
+        file.write_all(&chunk[..bytes_read])
+            .await
+            .map_err(|e| e.to_string())?;
+        upload.size += bytes_read;
+    }
+
+    file.flush().await.map_err(|e| e.to_string())?;
+    Ok(upload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn uploads_are_private_to_the_server_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut rest = &b" world"[..];
+        let dir = std::env::temp_dir();
+        let upload = stream_to_disk(&mut rest, b"hello", 11, &dir, None).await.unwrap();
+        assert_eq!(std::fs::read(upload.path()).unwrap(), b"hello world");
+        let mode = std::fs::metadata(upload.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let path = upload.path().to_path_buf();
+        drop(upload);
+        assert!(!path.exists());
+    }
+}